@@ -0,0 +1,60 @@
+// moves tokens between two chains: they're locked in an escrow account on the source and
+// minted as a wrapped token on the destination, so the locked balance backs the wrapped
+// supply one for one.
+
+use std::collections::HashMap;
+
+use crate::{Blockchain, Error, Method, Transaction};
+
+pub struct Bridge {
+    // account on the source chain holding everything locked
+    escrow: String,
+    // owner of the wrapped tokens on the destination chain, the only one allowed to mint them
+    minter: String,
+    // source token -> the token representing it on the destination
+    wrapped: HashMap<String, String>,
+}
+
+impl Bridge {
+    pub fn new(escrow: &str, minter: &str) -> Bridge {
+        Bridge {
+            escrow: escrow.into(),
+            minter: minter.into(),
+            wrapped: HashMap::new(),
+        }
+    }
+
+    pub fn with_route(mut self, token: &str, wrapped: &str) -> Bridge {
+        self.wrapped.insert(token.into(), wrapped.into());
+        self
+    }
+
+    // lock `amount` of `from`'s `token` on the source and mint as much of its wrapped token
+    // to `to` on the destination. if the mint fails, what was locked goes back to `from`
+    // and the mint's error is returned.
+    pub fn lock_and_mint(
+        &self,
+        source: &mut Blockchain,
+        dest: &mut Blockchain,
+        token: &str,
+        from: &str,
+        amount: u64,
+        to: &str,
+    ) -> Result<(), Error> {
+        let wrapped = self.wrapped.get(token).ok_or(Error::ContractNotFound)?;
+
+        source.process_transaction(transfer(source, from, token, amount, &self.escrow))?;
+        let minted = dest.batch_mint(wrapped, &self.minter, vec![(to.into(), amount)]);
+        if minted.is_err() {
+            source.process_transaction(transfer(source, &self.escrow, token, amount, from))?;
+        }
+        minted
+    }
+}
+
+// a transfer with the sender's next sequence on `chain`
+fn transfer(chain: &Blockchain, sender: &str, token: &str, amount: u64, to: &str) -> Transaction {
+    Transaction::new(sender, amount, token, Method::Transfer)
+        .with_seq(chain.next_valid_sequence(sender))
+        .with_destination(to)
+}
@@ -0,0 +1,98 @@
+// runs generated transactions against a chain and checks the invariants every run has to
+// keep, so a property test only has to come up with the transactions. supply is expected to
+// be conserved, which rules out contracts that mint or burn on transfer.
+
+use std::collections::HashMap;
+
+use crate::{Blockchain, Transaction};
+
+// the invariant a run broke
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Invariant {
+    // a contract's total supply changed
+    SupplyConservation,
+    // a balance came out larger than its contract's whole supply, which is what an
+    // underflowed, "negative" balance looks like
+    BalanceInRange,
+    // a sender's executed transactions didn't have strictly increasing sequences
+    MonotonicSequences,
+    // the block height didn't grow by exactly one per successful transaction
+    BlockHeight,
+}
+
+pub struct PropertyHarness {
+    blockchain: Blockchain,
+}
+
+impl PropertyHarness {
+    pub fn new(blockchain: Blockchain) -> PropertyHarness {
+        PropertyHarness { blockchain }
+    }
+
+    pub fn blockchain(&self) -> &Blockchain {
+        &self.blockchain
+    }
+
+    // process every transaction, failures included, then check the invariants. returns
+    // how many transactions succeeded.
+    pub fn run(&mut self, transactions: Vec<Transaction>) -> Result<usize, Invariant> {
+        let supply_before = self.supplies();
+        let height_before = self.blockchain.block_height;
+        let receipts_before = self.blockchain.receipts().len();
+
+        let succeeded = transactions
+            .into_iter()
+            .filter(|t| self.blockchain.process_transaction(t.clone()).is_ok())
+            .count();
+
+        if self.supplies() != supply_before {
+            return Err(Invariant::SupplyConservation);
+        }
+        for (contract, supply) in &supply_before {
+            let holders = self
+                .blockchain
+                .top_holders(contract, usize::MAX)
+                .unwrap_or_default();
+            if holders
+                .iter()
+                .any(|(_, balance)| *balance as u128 > *supply)
+            {
+                return Err(Invariant::BalanceInRange);
+            }
+        }
+
+        let mut last_sequence: HashMap<&str, u64> = HashMap::new();
+        for receipt in self.blockchain.receipts() {
+            if let Some(last) = last_sequence.insert(&receipt.sender, receipt.sequence) {
+                if receipt.sequence <= last {
+                    return Err(Invariant::MonotonicSequences);
+                }
+            }
+        }
+
+        if self.blockchain.block_height != height_before + succeeded as u64
+            || self.blockchain.receipts().len() != receipts_before + succeeded
+        {
+            return Err(Invariant::BlockHeight);
+        }
+        Ok(succeeded)
+    }
+
+    // total supply of every contract, widened so summing can't overflow
+    fn supplies(&self) -> HashMap<String, u128> {
+        self.blockchain
+            .contract_addresses()
+            .into_iter()
+            .map(|contract| {
+                let supply = self
+                    .blockchain
+                    .top_holders(&contract, usize::MAX)
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|(_, balance)| *balance as u128)
+                    .sum();
+                (contract, supply)
+            })
+            .collect()
+    }
+}
@@ -1,4 +1,20 @@
-use std::collections::HashMap;
+mod bridge;
+mod harness;
+mod math;
+mod ranking;
+mod storage;
+mod txlog;
+
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+use std::str::FromStr;
+
+use storage::Map;
+#[derive(Clone)]
 pub struct Transaction {
     // address of the sender
     pub sender: String,
@@ -13,19 +29,146 @@ pub struct Transaction {
 
     // destination
     pub destination: String,
+
+    // gas the sender is willing to buy, and the native price paid per unit
+    pub gas_limit: u64,
+    pub gas_price: u64,
+
+    // last block height at which the transaction may still execute
+    pub valid_until: Option<u64>,
+
+    // first block height at which the transaction may execute
+    pub valid_from: Option<u64>,
+
+    // free-form note carried into the transfer event and the receipt
+    pub memo: Option<String>,
+
+    // label for filtering history, like "swap" or "payment", carried into the receipt
+    pub category: Option<String>,
+
+    // what must hold for a conditional transfer to go ahead
+    pub condition: Option<Condition>,
+
+    // signature over the transaction hash, see `MockKeypair`
+    pub signature: Option<Vec<u8>>,
+
+    // highest base fee a metered transaction accepts
+    pub max_fee: u64,
+
+    // native amount paid on top of the fee to whoever proposes the block including it
+    pub tip: u64,
+
+    // the token a Method::Deploy transaction creates
+    pub deploy: Option<DeployParams>,
+}
+
+// what a deployed BasicToken starts out with
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct DeployParams {
+    pub name: String,
+    pub balances: Vec<(String, u64)>,
+}
+
+// a stand-in for a real keypair. the public key is derived from the secret, addresses from
+// the public key, and a signature carries the public key next to a digest of it and the
+// transaction hash. anyone can forge that digest, it's only meant to make tests sign.
+pub struct MockKeypair {
+    public: u64,
+}
+
+impl MockKeypair {
+    pub fn new(secret: u64) -> MockKeypair {
+        MockKeypair {
+            public: mock_digest(&[secret]),
+        }
+    }
+
+    pub fn address(&self) -> String {
+        mock_address(self.public)
+    }
+
+    pub fn sign(&self, transaction: &Transaction) -> Vec<u8> {
+        let digest = mock_digest(&[self.public, transaction.hash()]);
+        [self.public.to_be_bytes(), digest.to_be_bytes()].concat()
+    }
+}
+
+fn mock_digest(parts: &[u64]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    parts.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn mock_address(public: u64) -> String {
+    format!("0x{:016x}", mock_digest(&[public]))
+}
+
+// accepts a `MockKeypair` signature made by the sender over this exact transaction
+fn verify_signature(transaction: &Transaction, signature: &[u8]) -> bool {
+    if signature.len() != 16 {
+        return false;
+    }
+    let public = u64::from_be_bytes(signature[..8].try_into().unwrap());
+    let digest = u64::from_be_bytes(signature[8..].try_into().unwrap());
+    mock_address(public) == transaction.sender
+        && digest == mock_digest(&[public, transaction.hash()])
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Condition {
+    // the sender's balance must be at least this much
+    pub min_balance: u64,
 }
 
+// longest memo a transaction may carry, in bytes
+pub const MAX_MEMO_LEN: usize = 256;
+
+// furthest a transaction's sequence may jump ahead of the sender's current one, unless the
+// chain is configured otherwise
+pub const DEFAULT_MAX_SEQUENCE_GAP: u64 = 1_000;
+
+// native amount a deploy transaction pays, unless the chain is configured otherwise
+pub const DEFAULT_DEPLOY_FEE: u64 = 1_000;
+
+// seconds of simulated time per block, unless the chain is configured otherwise
+pub const DEFAULT_BLOCK_TIME_SECONDS: u64 = 12;
+
 impl Transaction {
     pub fn new(sender: &str, amount: u64, contract: &str, method: Method) -> Transaction {
-        return Transaction {
+        Transaction {
             sender: sender.into(),
-            amount: amount,
+            amount,
             contract: contract.into(),
-            method: method,
+            method,
 
             sequence: 0,
             destination: "".into(),
-        };
+
+            gas_limit: 0,
+            gas_price: 0,
+
+            valid_until: None,
+            valid_from: None,
+
+            memo: None,
+
+            category: None,
+
+            condition: None,
+
+            signature: None,
+
+            max_fee: 0,
+
+            tip: 0,
+
+            deploy: None,
+        }
+    }
+    // a transaction from the chain's default sender. unless set with `with_seq`, its
+    // sequence is assigned by the chain when the transaction is processed.
+    pub fn from_default(amount: u64, contract: &str, method: Method) -> Transaction {
+        Transaction::new("", amount, contract, method)
     }
     pub fn with_seq(mut self, seq: u64) -> Transaction {
         self.sequence = seq;
@@ -35,19 +178,239 @@ impl Transaction {
         self.destination = destination.into();
         self
     }
+    pub fn with_gas(mut self, gas_limit: u64, gas_price: u64) -> Transaction {
+        self.gas_limit = gas_limit;
+        self.gas_price = gas_price;
+        self
+    }
+    pub fn with_max_fee(mut self, max_fee: u64) -> Transaction {
+        self.max_fee = max_fee;
+        self
+    }
+    pub fn with_tip(mut self, tip: u64) -> Transaction {
+        self.tip = tip;
+        self
+    }
+    pub fn with_valid_until(mut self, height: u64) -> Transaction {
+        self.valid_until = Some(height);
+        self
+    }
+    pub fn with_valid_from(mut self, height: u64) -> Transaction {
+        self.valid_from = Some(height);
+        self
+    }
+    pub fn with_memo(mut self, memo: &str) -> Transaction {
+        self.memo = Some(memo.into());
+        self
+    }
+    pub fn with_category(mut self, category: &str) -> Transaction {
+        self.category = Some(category.into());
+        self
+    }
+    pub fn with_signature(mut self, signature: Vec<u8>) -> Transaction {
+        self.signature = Some(signature);
+        self
+    }
+    pub fn with_condition(mut self, min_balance: u64) -> Transaction {
+        self.condition = Some(Condition { min_balance });
+        self
+    }
+    pub fn with_deploy(mut self, name: &str, balances: Vec<(String, u64)>) -> Transaction {
+        self.deploy = Some(DeployParams {
+            name: name.into(),
+            balances,
+        });
+        self
+    }
+
+    // bytes the transaction takes up: every string and list at its length, a method at one
+    // byte and a number at eight, so a long memo or many deploy recipients make it large
+    pub fn size(&self) -> usize {
+        const NUMBER: usize = std::mem::size_of::<u64>();
+        let optional_numbers = [
+            self.valid_until,
+            self.valid_from,
+            self.condition.map(|c| c.min_balance),
+        ]
+        .iter()
+        .flatten()
+        .count();
+        let deploy = self.deploy.as_ref().map_or(0, |d| {
+            d.name.len()
+                + d.balances
+                    .iter()
+                    .map(|(a, _)| a.len() + NUMBER)
+                    .sum::<usize>()
+        });
+
+        self.sender.len()
+            + self.contract.len()
+            + 1
+            + self.destination.len()
+            + 6 * NUMBER
+            + optional_numbers * NUMBER
+            + self.memo.as_ref().map_or(0, |m| m.len())
+            + self.category.as_ref().map_or(0, |c| c.len())
+            + self.signature.as_ref().map_or(0, |s| s.len())
+            + deploy
+    }
+
+    // identifies a transaction, and is what gets signed. the memo and category are
+    // deliberately left out, so annotating a transaction doesn't change its identity.
+    pub fn hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.sender.hash(&mut hasher);
+        self.sequence.hash(&mut hasher);
+        self.amount.hash(&mut hasher);
+        self.contract.hash(&mut hasher);
+        self.method.as_str().hash(&mut hasher);
+        self.destination.hash(&mut hasher);
+        self.gas_limit.hash(&mut hasher);
+        self.gas_price.hash(&mut hasher);
+        self.max_fee.hash(&mut hasher);
+        self.tip.hash(&mut hasher);
+        self.valid_until.hash(&mut hasher);
+        self.valid_from.hash(&mut hasher);
+        self.condition.map(|c| c.min_balance).hash(&mut hasher);
+        self.deploy
+            .as_ref()
+            .map(|d| (&d.name, &d.balances))
+            .hash(&mut hasher);
+        hasher.finish()
+    }
 }
 
-#[derive(Copy, Clone, PartialEq, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub enum Error {
     NotEnoughBalance,
     ContractNotFound,
     BadTransactionSequence,
+    InsufficientFeeBalance,
+    OutOfGas,
+    UnknownMethod,
+    ContractAddressMismatch,
+    TransactionExpired,
+    ZeroTotalWeight,
+    BalanceOverflow,
+    MemoTooLong,
+    AccountExists,
+    AccountNotFound,
+    AccountNotEmpty,
+    InvalidSender,
+    InvalidDestination,
+    InvalidSignature,
+    FeeTooLow,
+    SequenceRegression,
+    Blacklisted,
+    AccountLimitReached,
+    // 1-based line of the input that couldn't be parsed
+    ParseError { line: usize },
+    CannotRollbackFinalized,
+    Unauthorized,
+    SupplyCapExceeded,
+    SelfTransfer,
+    GasPriceTooLow,
+    SequenceGapTooLarge,
+    VolumeCapExceeded,
+    BlockNotFound,
+    NotArchived,
+    TransactionNotYetValid,
+    NotWhitelisted,
+    RecipientNotAllowed,
+    TransferLimitExceeded,
+    ZeroEpochLength,
+    CooldownActive,
+    MethodDisabled,
+    ReserveRatioBreached,
+    TransactionTooLarge,
+    LendingNotEnabled,
+    // a transaction without a sender, on a chain without a default one to send it from
+    MissingSender,
 }
 
-#[derive(Copy, Clone, PartialEq, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub enum Method {
     BalanceOf,
     Transfer,
+    // transfer the sender's whole balance, returning the amount moved
+    TransferAll,
+    // transfer only if the sender meets the transaction's condition, returning the amount moved
+    ConditionalTransfer,
+    // transfer as much of the amount as the sender holds, returning the amount moved
+    TransferPartial,
+    // only use up the sequence. doesn't call, or even need, a contract.
+    Noop,
+    // create a BasicToken from the transaction's deploy params, at an address derived from
+    // the sender and sequence
+    Deploy,
+}
+
+impl Method {
+    // whether the method runs against the transaction's contract, which then has to exist
+    pub fn calls_contract(&self) -> bool {
+        !matches!(self, Method::Noop | Method::Deploy)
+    }
+
+    // whether the method moves tokens to the transaction's destination
+    pub fn is_transfer(&self) -> bool {
+        match self {
+            Method::BalanceOf | Method::Noop | Method::Deploy => false,
+            Method::Transfer
+            | Method::TransferAll
+            | Method::ConditionalTransfer
+            | Method::TransferPartial => true,
+        }
+    }
+
+    // canonical name, used by the RPC dispatcher and in serialized events
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::BalanceOf => "balance_of",
+            Method::Noop => "noop",
+            Method::Deploy => "deploy",
+            Method::Transfer => "transfer",
+            Method::TransferAll => "transfer_all",
+            Method::ConditionalTransfer => "conditional_transfer",
+            Method::TransferPartial => "transfer_partial",
+        }
+    }
+}
+
+impl FromStr for Method {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Method, Error> {
+        match s {
+            "balance_of" => Ok(Method::BalanceOf),
+            "noop" => Ok(Method::Noop),
+            "deploy" => Ok(Method::Deploy),
+            "transfer" => Ok(Method::Transfer),
+            "transfer_all" => Ok(Method::TransferAll),
+            "conditional_transfer" => Ok(Method::ConditionalTransfer),
+            "transfer_partial" => Ok(Method::TransferPartial),
+            _ => Err(Error::UnknownMethod),
+        }
+    }
+}
+
+// gas charged for every transaction, plus the per-method costs below
+pub const GAS_BASE: u64 = 21_000;
+pub const GAS_BALANCE_OF: u64 = 1_000;
+pub const GAS_TRANSFER: u64 = 5_000;
+// extra gas for a transfer that creates a new balance entry for the destination
+pub const GAS_NEW_ACCOUNT: u64 = 20_000;
+// extra gas for creating a contract
+pub const GAS_DEPLOY: u64 = 32_000;
+// the base fee moves by at most 1/8th per block
+pub const BASE_FEE_CHANGE_DENOMINATOR: u64 = 8;
+
+// broad family a contract belongs to, so generic tooling can branch on behavior
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ContractKind {
+    Fungible,
+    NonFungible,
+    Rebasing,
+    Wrapped,
 }
 
 pub trait TokenContract {
@@ -55,182 +418,6348 @@ pub trait TokenContract {
     fn contract(&self) -> String;
     fn balance_of(&self, address: String) -> u64;
     fn transfer(&mut self, sender: String, amount: u64, to: String) -> Result<(), Error>;
+    // transfer the way a boolean returning ERC20 does: true on success, and for a sender
+    // that can't afford it either false, or the error itself when strict
+    fn transfer_erc20(
+        &mut self,
+        sender: String,
+        amount: u64,
+        to: String,
+        strict: bool,
+    ) -> Result<bool, Error> {
+        match self.transfer(sender, amount, to) {
+            Ok(()) => Ok(true),
+            Err(Error::NotEnoughBalance) if !strict => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+    // stream every (address, balance) entry of the ledger, in no particular order
+    fn iter_balances(&self) -> Box<dyn Iterator<Item = (String, u64)> + '_> {
+        Box::new(std::iter::empty())
+    }
+    // every (address, balance) entry of the ledger, sorted by address
+    fn balances_snapshot(&self) -> Vec<(String, u64)> {
+        let mut entries: Vec<(String, u64)> = self.iter_balances().collect();
+        entries.sort();
+        entries
+    }
+    // credit every recipient, or nobody. only the contract's owner may mint, and a
+    // contract that doesn't support minting has no owner.
+    fn batch_mint(&mut self, _minter: &str, _recipients: Vec<(String, u64)>) -> Result<(), Error> {
+        Err(Error::Unauthorized)
+    }
+    // where a transfer destroys the tokens sent instead of crediting them, if anywhere
+    fn burn_address(&self) -> Option<String> {
+        None
+    }
+    // whether the contract currently takes calls to `method`. a disabled Transfer disables
+    // every other kind of transfer with it.
+    fn method_enabled(&self, _method: Method) -> bool {
+        true
+    }
+    // switch a method off or back on. only the contract's owner may, so like minting it's
+    // unauthorized on a contract without one.
+    fn set_method_enabled(
+        &mut self,
+        _caller: &str,
+        _method: Method,
+        _enabled: bool,
+    ) -> Result<(), Error> {
+        Err(Error::Unauthorized)
+    }
+    // replace the whole ledger, used to migrate state into an upgraded implementation
+    fn load_ledger(&mut self, entries: Vec<(String, u64)>);
+    fn kind(&self) -> ContractKind {
+        ContractKind::Fungible
+    }
+    // told the height of the block the calls that follow execute in, for a contract whose
+    // rules depend on it
+    fn set_block_height(&mut self, _height: u64) {}
+    // a copy of the contract and its state, used to rebuild a chain from genesis
+    fn box_clone(&self) -> Box<dyn TokenContract>;
 }
 
-pub struct BasicToken {
-    contract: String,
-    ledger: HashMap<String, u64>,
+impl Clone for Box<dyn TokenContract> {
+    fn clone(&self) -> Box<dyn TokenContract> {
+        self.box_clone()
+    }
 }
 
-impl BasicToken {
-    pub fn new(contract: String, airdrop_list: Vec<String>, initial_balance: u64) -> BasicToken {
-        let mut token = BasicToken {
-            contract,
-            ledger: HashMap::new(),
-        };
+// clone a map's entries out sorted by key, so enumeration never depends on HashMap order
+fn sorted_entries(map: &Map<String, u64>) -> Vec<(String, u64)> {
+    let mut entries: Vec<(String, u64)> = map.iter().map(|(k, v)| (k.clone(), *v)).collect();
+    entries.sort();
+    entries
+}
 
-        for addr in &airdrop_list {
-            // give initial balance of 1000
-            token.ledger.insert(addr.clone(), initial_balance);
+// run the transaction's method against its contract. returns the value handed back to the
+// caller, the amount moved if this was a transfer, and whether it was skipped.
+fn call_method(
+    contract: &mut dyn TokenContract,
+    transaction: &Transaction,
+    self_transfer_noop: bool,
+) -> Result<(u64, Option<u64>, bool), Error> {
+    // every kind of transfer moves tokens, so switching off Transfer switches them all off
+    if !contract.method_enabled(transaction.method)
+        || (transaction.method.is_transfer() && !contract.method_enabled(Method::Transfer))
+    {
+        return Err(Error::MethodDisabled);
+    }
+    let mut skipped = false;
+    let (value, transferred) = match transaction.method {
+        _ if self_transfer_noop => {
+            skipped = true;
+            (0, None)
         }
+        Method::BalanceOf => (contract.balance_of(transaction.sender.clone()), None),
+        Method::Noop | Method::Deploy => (0, None),
+        Method::Transfer => {
+            contract.transfer(
+                transaction.sender.clone(),
+                transaction.amount,
+                transaction.destination.clone(),
+            )?;
+            (0u64, Some(transaction.amount))
+        }
+        Method::TransferAll => {
+            let amount = contract.balance_of(transaction.sender.clone());
+            contract.transfer(
+                transaction.sender.clone(),
+                amount,
+                transaction.destination.clone(),
+            )?;
+            (amount, Some(amount))
+        }
+        Method::TransferPartial => {
+            let amount = transaction
+                .amount
+                .min(contract.balance_of(transaction.sender.clone()));
+            contract.transfer(
+                transaction.sender.clone(),
+                amount,
+                transaction.destination.clone(),
+            )?;
+            (amount, Some(amount))
+        }
+        Method::ConditionalTransfer => {
+            let min_balance = transaction
+                .condition
+                .map(|c| c.min_balance)
+                .unwrap_or_default();
+            if contract.balance_of(transaction.sender.clone()) < min_balance {
+                skipped = true;
+                (0, None)
+            } else {
+                contract.transfer(
+                    transaction.sender.clone(),
+                    transaction.amount,
+                    transaction.destination.clone(),
+                )?;
+                (transaction.amount, Some(transaction.amount))
+            }
+        }
+    };
+    Ok((value, transferred, skipped))
+}
 
-        token
+// the event a transaction that moved `amount` of `contract` emits, a burn if it was sent to
+// the contract's burn address
+fn transfer_event(contract: &dyn TokenContract, transaction: &Transaction, amount: u64) -> Event {
+    if contract.burn_address().as_ref() == Some(&transaction.destination) {
+        return Event::Burn {
+            contract: transaction.contract.clone(),
+            from: transaction.sender.clone(),
+            amount,
+        };
+    }
+    Event::Transfer {
+        contract: transaction.contract.clone(),
+        from: transaction.sender.clone(),
+        to: transaction.destination.clone(),
+        amount,
+        memo: transaction.memo.clone(),
     }
 }
 
-impl TokenContract for BasicToken {
-    fn contract(&self) -> String {
-        // let h = Hash
-        self.contract.clone()
+// whether a sender whose recent (block height, amount) transfers are `recent` may send
+// `amount` more in the block at `height`, under a volume cap of (max_volume, window_blocks).
+// only the transfers in the window_blocks blocks ending with that one count.
+fn check_volume_cap(
+    recent: &[(u64, u64)],
+    (max_volume, window_blocks): (u64, u64),
+    height: u64,
+    amount: u64,
+) -> Result<(), Error> {
+    let sent: u64 = recent
+        .iter()
+        .filter(|(h, _)| h.saturating_add(window_blocks) > height)
+        .map(|(_, amount)| *amount)
+        .sum();
+    if sent.saturating_add(amount) > max_volume {
+        return Err(Error::VolumeCapExceeded);
     }
-    fn balance_of(&self, address: String) -> u64 {
-        self.ledger.get(&address).map(|x| *x).unwrap_or_default()
+    Ok(())
+}
+
+// every balance a contract can enumerate, plus the given addresses even if it can't
+fn balances_with(contract: &dyn TokenContract, addresses: &[String]) -> HashMap<String, u64> {
+    let mut balances: HashMap<String, u64> = contract.iter_balances().collect();
+    for address in addresses {
+        balances.insert(address.clone(), contract.balance_of(address.clone()));
     }
-    fn transfer(&mut self, sender: String, amount: u64, to: String) -> Result<(), Error> {
-        println!(
-            "transfer from {} to {} of {} {} amount",
-            &sender, &to, amount, &self.contract
-        );
-        let mut balance = self.ledger.get(&sender).map(|x| *x).unwrap_or_default();
-        if amount > balance {
-            return Err(Error::NotEnoughBalance);
-        }
-        // lower balance of the source
-        balance -= amount;
-        self.ledger.insert(sender, balance);
+    balances
+}
 
-        // increase balance of the destination
-        let mut target_balance = self.ledger.get(&to).map(|x| *x).unwrap_or_default();
-        target_balance += amount;
-        self.ledger.insert(to, target_balance);
+// (address, old, new) for every balance that differs, `first` in the order given and then
+// the rest by address
+fn diff_balances(
+    before: &HashMap<String, u64>,
+    after: &HashMap<String, u64>,
+    first: &[String],
+) -> Vec<(String, u64, u64)> {
+    let mut rest: Vec<&String> = before
+        .keys()
+        .chain(after.keys())
+        .filter(|a| !first.contains(a))
+        .collect();
+    rest.sort();
+    let mut addresses: Vec<&String> = first.iter().chain(rest).collect();
+    let mut seen = HashSet::new();
+    addresses.retain(|a| seen.insert(*a));
 
-        Ok(())
+    addresses
+        .into_iter()
+        .map(|a| {
+            let old = before.get(a).copied().unwrap_or_default();
+            let new = after.get(a).copied().unwrap_or_default();
+            (a.clone(), old, new)
+        })
+        .filter(|(_, old, new)| old != new)
+        .collect()
+}
+
+// intercepts every transfer of a token, e.g. to tax, reroute or block it
+pub trait TransferHook {
+    // the amount the recipient should receive. returning an error aborts the transfer.
+    fn on_transfer(&mut self, from: &str, to: &str, amount: u64) -> Result<u64, Error>;
+    // where the part of a transfer the hook holds back is credited. None burns it.
+    fn withheld_to(&self) -> Option<String> {
+        None
     }
+    fn box_clone(&self) -> Box<dyn TransferHook>;
 }
 
-pub struct Blockchain {
-    pub block_height: u64,
-    contracts: Vec<Box<dyn TokenContract>>,
-    // track sequences for each address on this chain
-    accounts: HashMap<String, u64>,
+impl Clone for Box<dyn TransferHook> {
+    fn clone(&self) -> Box<dyn TransferHook> {
+        self.box_clone()
+    }
 }
 
-impl Blockchain {
-    pub fn new(contracts: Vec<Box<dyn TokenContract>>) -> Blockchain {
-        Blockchain {
-            block_height: 0,
-            accounts: HashMap::new(),
-            // instantiate two token contracts on the blockchain
-            contracts: contracts,
-        }
+// hands every transfer through unchanged
+#[derive(Clone)]
+pub struct IdentityHook;
+
+impl TransferHook for IdentityHook {
+    fn on_transfer(&mut self, _from: &str, _to: &str, amount: u64) -> Result<u64, Error> {
+        Ok(amount)
+    }
+    fn box_clone(&self) -> Box<dyn TransferHook> {
+        Box::new(self.clone())
     }
+}
 
-    pub fn validate_transaction_sequence(
-        &mut self,
-        transaction: &Transaction,
-    ) -> Result<(), Error> {
-        let current_sequence = self
-            .accounts
-            .get(&transaction.sender)
-            .map(|x| *x)
-            .unwrap_or_default();
-        if transaction.sequence <= current_sequence {
-            // invalid, the transaction sequence must increase!
-            Err(Error::BadTransactionSequence)
-        } else {
-            // update the sequence
-            self.accounts
-                .insert(transaction.sender.clone(), transaction.sequence);
-            Ok(())
+// withholds a percentage of every transfer, paid to the collector or burnt without one
+#[derive(Clone)]
+pub struct FeeHook {
+    bps: u16,
+    rounding: math::RoundingMode,
+    collector: Option<String>,
+}
+
+impl FeeHook {
+    pub fn new(bps: u16) -> FeeHook {
+        FeeHook {
+            bps: bps.min(math::BPS_DENOMINATOR as u16),
+            rounding: math::RoundingMode::default(),
+            collector: None,
         }
     }
 
-    pub fn process_transaction(&mut self, transaction: Transaction) -> Result<u64, Error> {
-        // first, validate the transaction
-        // 1. validate the signature (this is important to authenticate the transaction)
-        // (for brevity, this is ignored for now, but just assumed transactions are signed)
-
-        // 2. validate the transaction is not a replay.  if we don't do this, then bad things can happen.
-        self.validate_transaction_sequence(&transaction)?;
+    pub fn with_rounding(mut self, rounding: math::RoundingMode) -> FeeHook {
+        self.rounding = rounding;
+        self
+    }
 
-        // try to locate a contract
-        for contract in &mut self.contracts {
-            if contract.contract() == transaction.contract {
-                return match transaction.method {
-                    Method::BalanceOf => Ok(contract.balance_of(transaction.sender)),
-                    Method::Transfer => contract
-                        .transfer(
-                            transaction.sender,
-                            transaction.amount,
-                            transaction.destination,
-                        )
-                        .map(|_| 0u64),
-                };
-            }
-        }
-        // update the "blockhash"
-        self.block_height += 1;
+    pub fn with_collector(mut self, collector: &str) -> FeeHook {
+        self.collector = Some(collector.into());
+        self
+    }
+}
 
-        Err(Error::ContractNotFound)
+impl TransferHook for FeeHook {
+    fn on_transfer(&mut self, _from: &str, _to: &str, amount: u64) -> Result<u64, Error> {
+        Ok(amount - math::mul_bps_rounded(amount, self.bps, self.rounding))
+    }
+    fn withheld_to(&self) -> Option<String> {
+        self.collector.clone()
+    }
+    fn box_clone(&self) -> Box<dyn TransferHook> {
+        Box::new(self.clone())
     }
 }
 
-fn test_blockchain() -> Result<(), Error> {
-    println!("This is an example blockchain.");
-    let mut blockchain = Blockchain::new(vec![
-        Box::new(BasicToken::new(
-            "USDC".into(),
-            vec!["addr1".into(), "addr2".into()],
-            1000,
-        )),
-        Box::new(BasicToken::new(
-            "WBTC".into(),
-            vec!["addr3".into(), "addr4".into()],
-            1000,
-        )),
-    ]);
+#[derive(Clone)]
+pub struct BasicToken {
+    contract: String,
+    // human readable name, given at deploy
+    name: Option<String>,
+    ledger: Map<String, u64>,
+    hook: Box<dyn TransferHook>,
+    // drop emptied entries from the ledger after every transfer
+    auto_prune: bool,
+    // most entries the ledger may hold. pruning zero balances frees slots up.
+    max_accounts: Option<usize>,
+    // the only address allowed to mint, nobody if unset
+    owner: Option<String>,
+    supply_cap: Option<u64>,
+    // total supply the token was constructed with
+    genesis_supply: u64,
+    // how crediting a balance past u64::MAX is handled, by transfers and mints
+    arithmetic: math::ArithmeticMode,
+    // when set, the only addresses a transfer may send to. minting isn't restricted.
+    recipient_allowlist: Option<HashSet<String>>,
+    // most one transfer may move, as a share of the current total supply
+    max_transfer_bps_of_supply: Option<u16>,
+    // blocks a sender has to wait after a transfer before sending again, and the height
+    // each sender last sent at
+    transfer_cooldown_blocks: u64,
+    last_transfer: HashMap<String, u64>,
+    // height of the block calls currently execute in, as the chain last said
+    block_height: u64,
+    // methods the owner switched off
+    disabled_methods: HashSet<Method>,
+    // share of the outstanding loans the contract has to hold on its own address, when it
+    // lends at all, and how much it has lent out and not been repaid
+    reserve_ratio_bps: Option<u16>,
+    outstanding_loans: u64,
+    // transfers here reduce the supply rather than credit anybody
+    burn_address: Option<String>,
+}
 
-    let addr1_bal = blockchain
-        .process_transaction(Transaction::new("addr1", 0, "USDC", Method::BalanceOf).with_seq(1))?;
+impl BasicToken {
+    pub fn new(contract: String, airdrop_list: Vec<String>, initial_balance: u64) -> BasicToken {
+        let capacity = airdrop_list.len();
+        BasicToken::with_capacity(contract, airdrop_list, initial_balance, capacity)
+    }
 
-    let addr2_bal = blockchain
-        .process_transaction(Transaction::new("addr2", 0, "USDC", Method::BalanceOf).with_seq(1))?;
+    // like new, with the ledger sized up front for `capacity` holders
+    pub fn with_capacity(
+        contract: String,
+        airdrop_list: Vec<String>,
+        initial_balance: u64,
+        capacity: usize,
+    ) -> BasicToken {
+        let mut token = BasicToken {
+            contract,
+            name: None,
+            ledger: storage::with_capacity(capacity),
+            hook: Box::new(IdentityHook),
+            auto_prune: false,
+            max_accounts: None,
+            owner: None,
+            supply_cap: None,
+            genesis_supply: 0,
+            arithmetic: math::ArithmeticMode::default(),
+            recipient_allowlist: None,
+            max_transfer_bps_of_supply: None,
+            transfer_cooldown_blocks: 0,
+            last_transfer: HashMap::new(),
+            block_height: 0,
+            disabled_methods: HashSet::new(),
+            reserve_ratio_bps: None,
+            outstanding_loans: 0,
+            burn_address: None,
+        };
 
-    // initial balances of addresses are 1000
-    assert!(addr1_bal == 1000);
-    assert!(addr2_bal == 1000);
+        for addr in &airdrop_list {
+            // give initial balance of 1000
+            token.ledger.insert(addr.clone(), initial_balance);
+        }
+        token.genesis_supply = token.total_supply();
 
-    // repeating a transaction is an error.
-    let iserr = blockchain
-        .process_transaction(Transaction::new("addr1", 0, "USDC", Method::BalanceOf).with_seq(1));
-    assert!(iserr.is_err());
-    assert!(iserr.err().unwrap() == Error::BadTransactionSequence);
+        token
+    }
 
-    // test sending 100 USDC from addr1 to addr2 (increment sequence to 2)
-    let _ = blockchain.process_transaction(
-        Transaction::new("addr1", 100, "USDC", Method::Transfer)
-            .with_seq(2)
-            .with_destination("addr2"),
-    )?;
+    // build a token from `address,balance` lines. blank lines are skipped and an address
+    // listed more than once gets the sum of its balances.
+    pub fn from_csv(contract: String, csv: &str) -> Result<BasicToken, Error> {
+        let mut token = BasicToken::new(contract, vec![], 0);
 
-    // now lookup the balances
-    let addr1_bal = blockchain
-        .process_transaction(Transaction::new("addr1", 0, "USDC", Method::BalanceOf).with_seq(3))?;
+        for (i, line) in csv.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
 
-    let addr2_bal = blockchain
-        .process_transaction(Transaction::new("addr2", 0, "USDC", Method::BalanceOf).with_seq(3))?;
+            let parse_error = Error::ParseError { line: i + 1 };
+            let (address, balance) = line.split_once(',').ok_or(parse_error)?;
+            let address = address.trim();
+            if address.is_empty() {
+                return Err(parse_error);
+            }
+            let balance: u64 = balance.trim().parse().map_err(|_| parse_error)?;
 
-    // balances changed accordingly
-    assert!(addr1_bal == 900);
-    assert!(addr2_bal == 1100);
+            let entry = token.ledger.entry(address.to_string()).or_default();
+            *entry = entry.checked_add(balance).ok_or(Error::BalanceOverflow)?;
+        }
+        token.genesis_supply = token.total_supply();
 
-    Ok(())
-}
+        Ok(token)
+    }
 
-fn main() {
-    let r = test_blockchain();
-    if r.is_err() {
-        let s = format!("{:?}", r);
-        panic!("{}", s)
+    // a token holding exactly these balances. an address listed more than once gets the
+    // sum of its balances.
+    pub fn from_balances(
+        contract: String,
+        balances: Vec<(String, u64)>,
+    ) -> Result<BasicToken, Error> {
+        let mut token = BasicToken::with_capacity(contract, vec![], 0, balances.len());
+        token.import_accounts(balances)?;
+        token.genesis_supply = token.total_supply();
+        Ok(token)
+    }
+
+    pub fn with_name(mut self, name: &str) -> BasicToken {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn with_transfer_hook(mut self, hook: Box<dyn TransferHook>) -> BasicToken {
+        self.hook = hook;
+        self
+    }
+
+    pub fn with_auto_prune(mut self, auto_prune: bool) -> BasicToken {
+        self.auto_prune = auto_prune;
+        self
+    }
+
+    pub fn with_max_accounts(mut self, max_accounts: usize) -> BasicToken {
+        self.max_accounts = Some(max_accounts);
+        self
+    }
+
+    pub fn with_arithmetic_mode(mut self, arithmetic: math::ArithmeticMode) -> BasicToken {
+        self.arithmetic = arithmetic;
+        self
+    }
+
+    pub fn with_owner(mut self, owner: &str) -> BasicToken {
+        self.owner = Some(owner.into());
+        self
+    }
+
+    // only let transfers go to addresses the owner allows, none to begin with
+    pub fn with_recipient_allowlist(mut self) -> BasicToken {
+        self.recipient_allowlist = Some(HashSet::new());
+        self
+    }
+
+    // let transfers go to `address`. only the owner may, and only with the allowlist on.
+    pub fn allow_recipient(&mut self, caller: &str, address: &str) -> Result<(), Error> {
+        self.recipient_allowlist_of(caller)?.insert(address.into());
+        Ok(())
+    }
+
+    pub fn disallow_recipient(&mut self, caller: &str, address: &str) -> Result<(), Error> {
+        self.recipient_allowlist_of(caller)?.remove(address);
+        Ok(())
+    }
+
+    fn recipient_allowlist_of(&mut self, caller: &str) -> Result<&mut HashSet<String>, Error> {
+        if self.owner.as_deref() != Some(caller) {
+            return Err(Error::Unauthorized);
+        }
+        self.recipient_allowlist.as_mut().ok_or(Error::Unauthorized)
+    }
+
+    // cap every transfer at `bps` of the total supply, so the cap follows the supply
+    pub fn with_max_transfer_bps_of_supply(mut self, bps: u16) -> BasicToken {
+        self.max_transfer_bps_of_supply = Some(bps);
+        self
+    }
+
+    // make a sender wait `blocks` blocks after a transfer before sending again. receiving
+    // isn't affected.
+    pub fn with_transfer_cooldown(mut self, blocks: u64) -> BasicToken {
+        self.transfer_cooldown_blocks = blocks;
+        self
+    }
+
+    // let the contract lend, as long as its own balance stays at least `bps` of what it
+    // has lent out
+    pub fn with_reserve_ratio(mut self, bps: u16) -> BasicToken {
+        self.reserve_ratio_bps = Some(bps);
+        self
+    }
+
+    pub fn outstanding_loans(&self) -> u64 {
+        self.outstanding_loans
+    }
+
+    // burn whatever is transferred to `burn_address`, like "0x0". the recipient allowlist
+    // doesn't apply to it, the transfer limits and cooldown still do.
+    pub fn with_burn_address(mut self, burn_address: &str) -> BasicToken {
+        self.burn_address = Some(burn_address.into());
+        self
+    }
+
+    // most the total supply may grow to by minting
+    pub fn with_supply_cap(mut self, supply_cap: u64) -> BasicToken {
+        self.supply_cap = Some(supply_cap);
+        self
+    }
+
+    // the total supply, saturating at u64::MAX. see total_supply_u128 for the exact one.
+    pub fn total_supply(&self) -> u64 {
+        self.total_supply_u128().min(u64::MAX as u128) as u64
+    }
+
+    // every balance fits a u64, but their sum may not, so it's summed as a u128
+    pub fn total_supply_u128(&self) -> u128 {
+        self.ledger.values().map(|b| *b as u128).sum()
+    }
+
+    // the balances of just these addresses, in the order they were asked for
+    pub fn export_accounts(&self, addresses: &[String]) -> Vec<(String, u64)> {
+        addresses
+            .iter()
+            .map(|address| (address.clone(), self.balance_of(address.clone())))
+            .collect()
+    }
+
+    // add exported balances onto this ledger. an overflow imports nothing.
+    pub fn import_accounts(&mut self, entries: Vec<(String, u64)>) -> Result<(), Error> {
+        let mut credited: HashMap<String, u64> = HashMap::new();
+        for (address, amount) in entries {
+            let balance = match credited.get(&address) {
+                Some(balance) => *balance,
+                None => self.ledger.get(&address).copied().unwrap_or_default(),
+            };
+            let balance = balance.checked_add(amount).ok_or(Error::BalanceOverflow)?;
+            credited.insert(address, balance);
+        }
+
+        self.ledger.extend(credited);
+        Ok(())
+    }
+
+    // count the holders in each bucket of ascending boundaries: bucket i holds balances from
+    // buckets[i] up to but not including buckets[i + 1], and the last one everything from
+    // its boundary up. balances below the first boundary aren't counted.
+    pub fn balance_histogram(&self, buckets: &[u64]) -> Vec<usize> {
+        let mut counts = vec![0; buckets.len()];
+        for balance in self.ledger.values().filter(|b| **b > 0) {
+            let bucket = buckets.partition_point(|boundary| boundary <= balance);
+            if bucket > 0 {
+                counts[bucket - 1] += 1;
+            }
+        }
+        counts
+    }
+
+    // the total supply at construction, which minting doesn't change
+    pub fn genesis_supply(&self) -> u64 {
+        self.genesis_supply
+    }
+
+    // overwrite a balance directly, to arrange a starting state in a test. the supply
+    // moves by the difference, but the genesis supply stays what the token was
+    // constructed with.
+    #[cfg(feature = "test-utils")]
+    pub fn set_balance(&mut self, address: &str, amount: u64) {
+        self.ledger.insert(address.into(), amount);
+    }
+
+    // remove every zero balance from the ledger, returning how many were removed. an
+    // address that isn't in the ledger reads as 0, so no balance changes.
+    pub fn prune_zero_balances(&mut self) -> usize {
+        let before = self.ledger.len();
+        self.ledger.retain(|_, balance| *balance > 0);
+        before - self.ledger.len()
+    }
+
+    // distribute `total` across addresses in proportion to their weights. the rounding
+    // remainder goes to the largest weight (the first one listed on a tie), so exactly
+    // `total` is handed out.
+    pub fn airdrop_weighted(
+        &mut self,
+        total: u64,
+        weights: Vec<(String, u32)>,
+    ) -> Result<(), Error> {
+        let total_weight: u128 = weights.iter().map(|(_, w)| *w as u128).sum();
+        if total_weight == 0 {
+            return Err(Error::ZeroTotalWeight);
+        }
+
+        let mut shares: Vec<u64> = weights
+            .iter()
+            .map(|(_, w)| (total as u128 * *w as u128 / total_weight) as u64)
+            .collect();
+        let remainder = total - shares.iter().sum::<u64>();
+        let mut largest = 0;
+        for (i, (_, w)) in weights.iter().enumerate() {
+            if *w > weights[largest].1 {
+                largest = i;
+            }
+        }
+        shares[largest] += remainder;
+
+        // work out every new balance before touching the ledger, so an overflow credits nobody
+        let mut credited: HashMap<String, u64> = HashMap::new();
+        for ((address, _), share) in weights.into_iter().zip(shares) {
+            let balance = match credited.get(&address) {
+                Some(balance) => *balance,
+                None => self.ledger.get(&address).copied().unwrap_or_default(),
+            };
+            let balance = balance.checked_add(share).ok_or(Error::BalanceOverflow)?;
+            credited.insert(address, balance);
+        }
+        self.ledger.extend(credited);
+
+        Ok(())
+    }
+
+    // credit `to` with newly created tokens on loan. the contract's reserves back only a
+    // fraction of what it lends, so it may lend out more than it holds. like minting, only
+    // the owner may, and the loan has to fit the recipient and supply limits.
+    pub fn lend(&mut self, lender: &str, to: &str, amount: u64) -> Result<(), Error> {
+        let ratio = self.reserve_ratio_bps.ok_or(Error::LendingNotEnabled)?;
+        if self.owner.as_deref() != Some(lender) {
+            return Err(Error::Unauthorized);
+        }
+        if self
+            .recipient_allowlist
+            .as_ref()
+            .is_some_and(|allowlist| !allowlist.contains(to))
+        {
+            return Err(Error::RecipientNotAllowed);
+        }
+        if let Some(max_accounts) = self.max_accounts {
+            if !self.ledger.contains_key(to) && self.ledger.len() >= max_accounts {
+                return Err(Error::AccountLimitReached);
+            }
+        }
+        if self
+            .supply_cap
+            .is_some_and(|cap| self.total_supply_u128() + amount as u128 > cap as u128)
+        {
+            return Err(Error::SupplyCapExceeded);
+        }
+        let outstanding = self
+            .outstanding_loans
+            .checked_add(amount)
+            .ok_or(Error::BalanceOverflow)?;
+        let reserves = self.balance_of(self.contract.clone());
+        if reserves < math::mul_bps_rounded(outstanding, ratio, math::RoundingMode::Ceil) {
+            return Err(Error::ReserveRatioBreached);
+        }
+        let balance = self
+            .arithmetic
+            .add(self.balance_of(to.into()), amount)
+            .ok_or(Error::BalanceOverflow)?;
+
+        self.ledger.insert(to.into(), balance);
+        self.outstanding_loans = outstanding;
+        Ok(())
+    }
+
+    // pay back up to `amount` of the outstanding loans out of `from`'s balance, destroying
+    // the tokens repaid. returns how much was repaid.
+    pub fn repay(&mut self, from: &str, amount: u64) -> Result<u64, Error> {
+        let amount = amount.min(self.outstanding_loans);
+        let balance = self.balance_of(from.into());
+        if amount > balance {
+            return Err(Error::NotEnoughBalance);
+        }
+
+        self.ledger.insert(from.into(), balance - amount);
+        self.outstanding_loans -= amount;
+        Ok(amount)
+    }
+}
+
+impl TokenContract for BasicToken {
+    fn contract(&self) -> String {
+        // let h = Hash
+        self.contract.clone()
+    }
+    fn balance_of(&self, address: String) -> u64 {
+        self.ledger.get(&address).copied().unwrap_or_default()
+    }
+    fn transfer(&mut self, sender: String, amount: u64, to: String) -> Result<(), Error> {
+        println!(
+            "transfer from {} to {} of {} {} amount",
+            &sender, &to, amount, &self.contract
+        );
+        // burning isn't receiving, so the burn address never needs allowing
+        let burn = self.burn_address.as_ref() == Some(&to);
+        if !burn
+            && self
+                .recipient_allowlist
+                .as_ref()
+                .is_some_and(|allowlist| !allowlist.contains(&to))
+        {
+            return Err(Error::RecipientNotAllowed);
+        }
+        if let Some(bps) = self.max_transfer_bps_of_supply {
+            if amount > math::mul_bps(self.total_supply(), bps) {
+                return Err(Error::TransferLimitExceeded);
+            }
+        }
+        if self.transfer_cooldown_blocks > 0
+            && self.last_transfer.get(&sender).is_some_and(|last| {
+                self.block_height < last.saturating_add(self.transfer_cooldown_blocks)
+            })
+        {
+            return Err(Error::CooldownActive);
+        }
+        let mut balance = self.ledger.get(&sender).copied().unwrap_or_default();
+        if amount > balance {
+            return Err(Error::NotEnoughBalance);
+        }
+        // a burn only takes from the sender, so it never creates an account or passes
+        // through the hook
+        if burn {
+            self.ledger.insert(sender.clone(), balance - amount);
+            if self.transfer_cooldown_blocks > 0 {
+                self.last_transfer.insert(sender, self.block_height);
+            }
+            if self.auto_prune {
+                self.prune_zero_balances();
+            }
+            return Ok(());
+        }
+        // a transfer to an existing account is always allowed, a new one needs a free slot
+        if let Some(max_accounts) = self.max_accounts {
+            if !self.ledger.contains_key(&to) && self.ledger.len() >= max_accounts {
+                return Err(Error::AccountLimitReached);
+            }
+        }
+        // the hook runs before any balance moves. it can only hold back part of the
+        // amount, never add to it.
+        let received = self.hook.on_transfer(&sender, &to, amount)?.min(amount);
+
+        // lower balance of the source
+        balance -= amount;
+        let mut updated = vec![(sender.clone(), balance)];
+
+        // increase balance of the destination, and whatever the hook held back goes where it
+        // says, or is burned. every new balance is worked out before the ledger is touched,
+        // so an overflow moves nothing.
+        let mut credits = vec![(to, received)];
+        if let Some(withheld_to) = self.hook.withheld_to() {
+            credits.push((withheld_to, amount - received));
+        }
+        for (address, credit) in credits {
+            let current = match updated.iter().rev().find(|(a, _)| *a == address) {
+                Some((_, balance)) => *balance,
+                None => self.ledger.get(&address).copied().unwrap_or_default(),
+            };
+            let balance = self
+                .arithmetic
+                .add(current, credit)
+                .ok_or(Error::BalanceOverflow)?;
+            updated.push((address, balance));
+        }
+        self.ledger.extend(updated);
+        if self.transfer_cooldown_blocks > 0 {
+            self.last_transfer.insert(sender, self.block_height);
+        }
+
+        if self.auto_prune {
+            self.prune_zero_balances();
+        }
+
+        Ok(())
+    }
+    fn batch_mint(&mut self, minter: &str, recipients: Vec<(String, u64)>) -> Result<(), Error> {
+        if self.owner.as_deref() != Some(minter) {
+            return Err(Error::Unauthorized);
+        }
+
+        // work out every new balance before touching the ledger, so a failure mints nothing
+        let minted: u128 = recipients.iter().map(|(_, amount)| *amount as u128).sum();
+        let mut credited: HashMap<String, u64> = HashMap::new();
+        for (address, amount) in recipients {
+            let balance = match credited.get(&address) {
+                Some(balance) => *balance,
+                None => self.ledger.get(&address).copied().unwrap_or_default(),
+            };
+            let balance = self
+                .arithmetic
+                .add(balance, amount)
+                .ok_or(Error::BalanceOverflow)?;
+            credited.insert(address, balance);
+        }
+        if self
+            .supply_cap
+            .is_some_and(|cap| self.total_supply_u128() + minted > cap as u128)
+        {
+            return Err(Error::SupplyCapExceeded);
+        }
+
+        self.ledger.extend(credited);
+        Ok(())
+    }
+    fn iter_balances(&self) -> Box<dyn Iterator<Item = (String, u64)> + '_> {
+        Box::new(self.ledger.iter().map(|(k, v)| (k.clone(), *v)))
+    }
+    fn set_block_height(&mut self, height: u64) {
+        self.block_height = height;
+    }
+    fn burn_address(&self) -> Option<String> {
+        self.burn_address.clone()
+    }
+    fn method_enabled(&self, method: Method) -> bool {
+        !self.disabled_methods.contains(&method)
+    }
+    fn set_method_enabled(
+        &mut self,
+        caller: &str,
+        method: Method,
+        enabled: bool,
+    ) -> Result<(), Error> {
+        if self.owner.as_deref() != Some(caller) {
+            return Err(Error::Unauthorized);
+        }
+        if enabled {
+            self.disabled_methods.remove(&method);
+        } else {
+            self.disabled_methods.insert(method);
+        }
+        Ok(())
+    }
+    fn load_ledger(&mut self, entries: Vec<(String, u64)>) {
+        self.ledger = entries.into_iter().collect();
+    }
+    fn box_clone(&self) -> Box<dyn TokenContract> {
+        Box::new(self.clone())
+    }
+}
+
+// a vault over an underlying token. depositors receive shares, and the share price grows
+// as yield accrues. the vault only does the accounting, moving the underlying token in and
+// out is left to whoever calls deposit and withdraw.
+#[derive(Clone)]
+pub struct VaultContract {
+    contract: String,
+    underlying: String,
+    shares: HashMap<String, u64>,
+    total_shares: u64,
+    // underlying owed to share holders: deposits plus accrued yield, minus withdrawals
+    total_assets: u64,
+    // yield added per block, in basis points of total_assets
+    rate_bps: u16,
+}
+
+impl VaultContract {
+    pub fn new(contract: String, underlying: String, rate_bps: u16) -> VaultContract {
+        VaultContract {
+            contract,
+            underlying,
+            shares: HashMap::new(),
+            total_shares: 0,
+            total_assets: 0,
+            rate_bps,
+        }
+    }
+
+    pub fn underlying(&self) -> String {
+        self.underlying.clone()
+    }
+
+    pub fn total_assets(&self) -> u64 {
+        self.total_assets
+    }
+
+    // underlying a number of shares can be redeemed for at the current price
+    pub fn preview_withdraw(&self, shares: u64) -> u64 {
+        if self.total_shares == 0 {
+            return 0;
+        }
+        (shares as u128 * self.total_assets as u128 / self.total_shares as u128) as u64
+    }
+
+    // mint shares for underlying deposited at the current price, returning the shares
+    // minted. this doesn't take the underlying from `user`, the caller has to move it to
+    // the vault as well.
+    pub fn deposit(&mut self, user: String, amount: u64) -> Result<u64, Error> {
+        let minted = if self.total_shares == 0 || self.total_assets == 0 {
+            amount
+        } else {
+            (amount as u128 * self.total_shares as u128 / self.total_assets as u128) as u64
+        };
+        let total_assets = self
+            .total_assets
+            .checked_add(amount)
+            .ok_or(Error::BalanceOverflow)?;
+        let total_shares = self
+            .total_shares
+            .checked_add(minted)
+            .ok_or(Error::BalanceOverflow)?;
+        // no holder has more than the total, so this can't overflow once the total didn't
+        *self.shares.entry(user).or_default() += minted;
+        self.total_assets = total_assets;
+        self.total_shares = total_shares;
+        Ok(minted)
+    }
+
+    pub fn accrue(&mut self, blocks: u64) {
+        let interest = math::mul_bps_periods(self.total_assets, self.rate_bps, blocks);
+        self.total_assets = self.total_assets.saturating_add(interest);
+    }
+
+    // burn shares and return the underlying they are worth. rounds down, so the vault
+    // never pays out more than it holds.
+    pub fn withdraw(&mut self, user: String, shares: u64) -> Result<u64, Error> {
+        let balance = self.shares.get(&user).copied().unwrap_or_default();
+        if shares > balance {
+            return Err(Error::NotEnoughBalance);
+        }
+        let amount = self.preview_withdraw(shares);
+        self.shares.insert(user, balance - shares);
+        self.total_shares -= shares;
+        self.total_assets -= amount;
+        Ok(amount)
+    }
+}
+
+// the share ledger is itself a token, so shares can be moved around on chain
+impl TokenContract for VaultContract {
+    fn contract(&self) -> String {
+        self.contract.clone()
+    }
+    fn balance_of(&self, address: String) -> u64 {
+        self.shares.get(&address).copied().unwrap_or_default()
+    }
+    fn transfer(&mut self, sender: String, amount: u64, to: String) -> Result<(), Error> {
+        let balance = self.balance_of(sender.clone());
+        if amount > balance {
+            return Err(Error::NotEnoughBalance);
+        }
+        if sender == to {
+            return Ok(());
+        }
+        let received = self
+            .balance_of(to.clone())
+            .checked_add(amount)
+            .ok_or(Error::BalanceOverflow)?;
+        self.shares.insert(sender, balance - amount);
+        self.shares.insert(to, received);
+        Ok(())
+    }
+    fn iter_balances(&self) -> Box<dyn Iterator<Item = (String, u64)> + '_> {
+        Box::new(self.shares.iter().map(|(k, v)| (k.clone(), *v)))
+    }
+    fn load_ledger(&mut self, entries: Vec<(String, u64)>) {
+        self.shares = entries.into_iter().collect();
+        self.total_shares = self
+            .shares
+            .values()
+            .fold(0, |total, s| total.saturating_add(*s));
+    }
+    fn box_clone(&self) -> Box<dyn TokenContract> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Event {
+    Transfer {
+        contract: String,
+        from: String,
+        to: String,
+        amount: u64,
+        memo: Option<String>,
+    },
+    Mint {
+        contract: String,
+        to: String,
+        amount: u64,
+    },
+    Burn {
+        contract: String,
+        from: String,
+        amount: u64,
+    },
+    // a permissive chain took a sequence it should have rejected, moving the sender's
+    // sequence from `from` to `to`
+    SequenceResync {
+        address: String,
+        from: u64,
+        to: u64,
+    },
+}
+
+impl Event {
+    // the contract that emitted the event
+    pub fn contract(&self) -> &str {
+        match self {
+            Event::Transfer { contract, .. }
+            | Event::Mint { contract, .. }
+            | Event::Burn { contract, .. } => contract,
+            // the chain's own events don't belong to any contract
+            Event::SequenceResync { .. } => "",
+        }
+    }
+
+    // what the event can be looked up by: its type, then every address it involves
+    pub fn topics(&self) -> Vec<&str> {
+        match self {
+            Event::Transfer { from, to, .. } => vec!["transfer", from, to],
+            Event::Mint { to, .. } => vec!["mint", to],
+            Event::Burn { from, .. } => vec!["burn", from],
+            Event::SequenceResync { address, .. } => vec!["sequence_resync", address],
+        }
+    }
+}
+
+// net effect of one mutation on one address's balance
+#[derive(Clone, PartialEq, Debug)]
+pub struct BalanceChange {
+    pub contract: String,
+    pub address: String,
+    pub old: u64,
+    pub new: u64,
+    // block the change was made in, or the height it was made at outside of any block
+    pub height: u64,
+}
+
+// what a transaction would do if it were processed now, see `Blockchain::simulate`
+#[derive(Clone, PartialEq, Debug)]
+pub struct Simulation {
+    pub value: u64,
+    // amount moved, if it's a transfer that wasn't skipped
+    pub transferred: Option<u64>,
+    pub skipped: bool,
+    // at the height of the block that would include it
+    pub changes: Vec<BalanceChange>,
+}
+
+// handle returned by `Blockchain::subscribe`, used to unsubscribe again
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct SubscriptionId(u64);
+
+struct Subscription {
+    id: SubscriptionId,
+    contract: String,
+    callback: Box<dyn FnMut(&Event)>,
+}
+
+// record of a transaction that executed
+#[derive(Clone, PartialEq, Debug)]
+pub struct TransactionReceipt {
+    pub hash: u64,
+    pub sender: String,
+    pub sequence: u64,
+    pub block_height: u64,
+    pub method: Method,
+    // the contract that handled the transaction, none for a noop
+    pub contract: Option<String>,
+    // what process_transaction returned
+    pub value: u64,
+    pub memo: Option<String>,
+    pub category: Option<String>,
+    // a conditional transfer whose condition didn't hold
+    pub skipped: bool,
+    // handed back again for a duplicate submission, rather than from executing it
+    pub cached: bool,
+}
+
+impl TransactionReceipt {
+    // compare everything but the hash, for expected receipts built without one
+    pub fn matches(&self, other: &TransactionReceipt) -> bool {
+        TransactionReceipt {
+            hash: other.hash,
+            ..self.clone()
+        } == *other
+    }
+}
+
+// builds expected receipts for tests. everything not set is zero, empty or false.
+pub struct ReceiptBuilder {
+    receipt: TransactionReceipt,
+}
+
+impl ReceiptBuilder {
+    pub fn new(sender: &str, method: Method) -> ReceiptBuilder {
+        ReceiptBuilder {
+            receipt: TransactionReceipt {
+                hash: 0,
+                sender: sender.into(),
+                sequence: 0,
+                block_height: 0,
+                method,
+                contract: None,
+                value: 0,
+                memo: None,
+                category: None,
+                skipped: false,
+                cached: false,
+            },
+        }
+    }
+    pub fn hash(mut self, hash: u64) -> ReceiptBuilder {
+        self.receipt.hash = hash;
+        self
+    }
+    pub fn sequence(mut self, sequence: u64) -> ReceiptBuilder {
+        self.receipt.sequence = sequence;
+        self
+    }
+    pub fn block_height(mut self, block_height: u64) -> ReceiptBuilder {
+        self.receipt.block_height = block_height;
+        self
+    }
+    pub fn contract(mut self, contract: &str) -> ReceiptBuilder {
+        self.receipt.contract = Some(contract.into());
+        self
+    }
+    pub fn value(mut self, value: u64) -> ReceiptBuilder {
+        self.receipt.value = value;
+        self
+    }
+    pub fn memo(mut self, memo: &str) -> ReceiptBuilder {
+        self.receipt.memo = Some(memo.into());
+        self
+    }
+    pub fn category(mut self, category: &str) -> ReceiptBuilder {
+        self.receipt.category = Some(category.into());
+        self
+    }
+    pub fn skipped(mut self, skipped: bool) -> ReceiptBuilder {
+        self.receipt.skipped = skipped;
+        self
+    }
+    pub fn cached(mut self, cached: bool) -> ReceiptBuilder {
+        self.receipt.cached = cached;
+        self
+    }
+    pub fn build(self) -> TransactionReceipt {
+        self.receipt
+    }
+}
+
+#[derive(Clone)]
+pub struct Block {
+    pub height: u64,
+    pub transactions: Vec<Transaction>,
+    pub gas_used: u64,
+    // the coinbase "transaction": who was paid the block reward and the tips, and how much
+    pub coinbase: Option<(String, u64)>,
+}
+
+impl Block {
+    // identifies a block by everything in it
+    pub fn hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.height.hash(&mut hasher);
+        for transaction in &self.transactions {
+            transaction.hash().hash(&mut hasher);
+        }
+        self.gas_used.hash(&mut hasher);
+        self.coinbase.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    // a pseudo-random value derived from the block's hash: the same block always gives the
+    // same value, so a replayed chain sees the same randomness. predictable by anyone, so
+    // only for mocks.
+    pub fn random_beacon(&self) -> u64 {
+        mock_digest(&[self.hash()])
+    }
+}
+
+// counters over every transaction submitted to a chain
+#[derive(Clone, Default, Debug)]
+pub struct Metrics {
+    pub processed: u64,
+    pub succeeded: u64,
+    // keyed by the kind of error, so errors carrying data, like a parse error's line,
+    // count together
+    pub failures: HashMap<std::mem::Discriminant<Error>, u64>,
+    // total amount successful transfers delivered, or burnt, which can be less than they
+    // asked to move
+    pub volume: u64,
+}
+
+impl Metrics {
+    fn record(&mut self, result: &Result<u64, Error>) {
+        self.processed += 1;
+        match result {
+            Ok(_) => self.succeeded += 1,
+            Err(e) => *self.failures.entry(std::mem::discriminant(e)).or_default() += 1,
+        }
+    }
+
+    // how many transactions failed with the same kind of error as `error`
+    pub fn failures_of(&self, error: &Error) -> u64 {
+        self.failures
+            .get(&std::mem::discriminant(error))
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+// works out the native fee a transaction pays on top of its gas, so tests can model any
+// fee scheme
+pub trait FeeCalculator {
+    fn calculate(&self, transaction: &Transaction, chain: &Blockchain) -> u64;
+}
+
+// the same fee for every transaction
+pub struct FlatFee {
+    fee: u64,
+}
+
+impl FlatFee {
+    pub fn new(fee: u64) -> FlatFee {
+        FlatFee { fee }
+    }
+}
+
+impl FeeCalculator for FlatFee {
+    fn calculate(&self, _transaction: &Transaction, _chain: &Blockchain) -> u64 {
+        self.fee
+    }
+}
+
+pub struct Blockchain {
+    pub block_height: u64,
+    // height the chain starts at, e.g. when it forks off or is restored from another one.
+    // the first block sealed is the one above it.
+    genesis_height: u64,
+    contracts: Vec<Box<dyn TokenContract>>,
+    // the contracts as they were deployed, to replay the chain from
+    genesis: Vec<Box<dyn TokenContract>>,
+    // track sequences for each address on this chain
+    accounts: Map<String, u64>,
+    // native balances, used to pay for gas
+    native: Map<String, u64>,
+    // balance after each block that changed it, keyed by (contract, address)
+    balance_history: HashMap<(String, String), Vec<(u64, u64)>>,
+    // sender used for transactions built with `Transaction::from_default`
+    default_sender: Option<String>,
+    metrics: Metrics,
+    events: Vec<Event>,
+    // topic -> indexes into events, in emission order
+    event_topics: HashMap<String, Vec<usize>>,
+    receipts: Vec<TransactionReceipt>,
+    balance_changes: Vec<BalanceChange>,
+    // sequence every unseen account starts from, so its first transaction uses genesis_nonce + 1
+    genesis_nonce: u64,
+    // furthest ahead of the current sequence a transaction may skip
+    max_sequence_gap: u64,
+    sequence_mode: SequenceMode,
+    routing_mode: RoutingMode,
+    // last sequence of every deleted account, kept so a deleted account can't be replayed
+    retired_sequences: HashMap<String, u64>,
+    blocks: Vec<Block>,
+    // native amount minted to the coinbase for every block
+    block_reward: u64,
+    coinbase: String,
+    // take turns proposing blocks, and are paid the block reward instead of the coinbase
+    validators: Vec<String>,
+    // whether a transfer may be sent to a contract's own address
+    allow_contract_destination: bool,
+    // addresses that may neither send nor receive
+    blacklist: HashSet<String>,
+    // when whitelist_mode is on, the only addresses that may send
+    whitelist_mode: bool,
+    whitelist: HashSet<String>,
+    // price every metered transaction pays, and burns, on top of its gas, and the gas per
+    // block it steers towards. a zero target keeps the base fee fixed.
+    base_fee: u64,
+    gas_target: u64,
+    // lowest gas price a metered transaction may offer
+    min_gas_price: u64,
+    subscriptions: Vec<Subscription>,
+    next_subscription_id: u64,
+    // blocks built on top of a block before it's final and can no longer be rolled back
+    confirmations_required: u64,
+    // the state just before every block that isn't final yet, oldest first, to roll back to
+    rollback_points: Vec<Checkpoint>,
+    self_transfer_policy: SelfTransferPolicy,
+    // (max_volume, window_blocks): most a sender may send of one token over any run of
+    // window_blocks blocks
+    volume_cap: Option<(u64, u64)>,
+    // (block height, amount) of recent outgoing transfers, keyed by (contract, sender)
+    outgoing_volume: HashMap<(String, String), Vec<(u64, u64)>>,
+    // whether every contract's ledger is kept after every block
+    archive: bool,
+    // native amount burnt by every deploy transaction
+    deploy_fee: u64,
+    // largest transaction the chain takes, see Transaction::size
+    max_tx_size: usize,
+    // native amount burnt by every transaction, a flat fee of nothing unless configured
+    fee_calculator: Box<dyn FeeCalculator>,
+    // seconds `advance_time` needs to produce a block, and the seconds it's been advanced
+    // by since the last one
+    block_time_seconds: u64,
+    elapsed_seconds: u64,
+    // height -> contract -> ledger, for every block sealed while archiving
+    snapshots: HashMap<u64, HashMap<String, HashMap<String, u64>>>,
+    // blocks in every epoch. a chain that isn't configured with epochs stays in epoch 0.
+    blocks_per_epoch: u64,
+    // called with the new epoch whenever a sealed block starts one
+    epoch_hook: Option<Box<dyn FnMut(u64)>>,
+}
+
+// what a transfer with the sender as its own destination does
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub enum SelfTransferPolicy {
+    // run it like any other transfer
+    #[default]
+    Allow,
+    // reject it with Error::SelfTransfer
+    Deny,
+    // succeed without touching any balance, still using up the sequence
+    NoOp,
+}
+
+// which sequences, above the sender's current one, a transaction may use
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub enum SequenceMode {
+    // any higher sequence, up to the max sequence gap ahead
+    #[default]
+    Monotonic,
+    // only the very next one
+    Strict,
+    // anything at all. a sequence monotonic mode would reject is taken anyway and becomes
+    // the sender's sequence, with a SequenceResync event the only sign of it. this reopens
+    // replays, so it's only for chaos testing.
+    Permissive,
+}
+
+// how a transaction picks the contract it calls
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub enum RoutingMode {
+    // the one named by its contract field
+    #[default]
+    ByContractField,
+    // the one at its destination, whatever the contract field says. a transfer then pays
+    // the contract's own address, like sending to a contract account.
+    ByDestination,
+}
+
+// chain state saved by `Blockchain::process_atomic`, to restore if the batch fails
+struct Checkpoint {
+    block_height: u64,
+    contracts: Vec<Box<dyn TokenContract>>,
+    accounts: Map<String, u64>,
+    native: Map<String, u64>,
+    balance_history: HashMap<(String, String), Vec<(u64, u64)>>,
+    events: usize,
+    receipts: usize,
+    balance_changes: usize,
+    blocks: usize,
+    base_fee: u64,
+    outgoing_volume: HashMap<(String, String), Vec<(u64, u64)>>,
+    metrics: Metrics,
+}
+
+impl Blockchain {
+    pub fn new(contracts: Vec<Box<dyn TokenContract>>) -> Blockchain {
+        Blockchain::with_capacity(contracts, 0)
+    }
+
+    // like new, with room for `capacity` accounts up front
+    pub fn with_capacity(contracts: Vec<Box<dyn TokenContract>>, capacity: usize) -> Blockchain {
+        Blockchain {
+            block_height: 0,
+            genesis_height: 0,
+            accounts: storage::with_capacity(capacity),
+            native: storage::with_capacity(capacity),
+            balance_history: HashMap::new(),
+            default_sender: None,
+            metrics: Metrics::default(),
+            events: Vec::new(),
+            event_topics: HashMap::new(),
+            receipts: Vec::new(),
+            balance_changes: Vec::new(),
+            genesis_nonce: 0,
+            max_sequence_gap: DEFAULT_MAX_SEQUENCE_GAP,
+            sequence_mode: SequenceMode::default(),
+            routing_mode: RoutingMode::default(),
+            retired_sequences: HashMap::new(),
+            blocks: Vec::new(),
+            block_reward: 0,
+            coinbase: "".into(),
+            validators: Vec::new(),
+            allow_contract_destination: false,
+            blacklist: HashSet::new(),
+            whitelist_mode: false,
+            whitelist: HashSet::new(),
+            base_fee: 0,
+            gas_target: 0,
+            min_gas_price: 0,
+            subscriptions: Vec::new(),
+            next_subscription_id: 0,
+            confirmations_required: 0,
+            rollback_points: Vec::new(),
+            self_transfer_policy: SelfTransferPolicy::default(),
+            volume_cap: None,
+            outgoing_volume: HashMap::new(),
+            archive: false,
+            deploy_fee: DEFAULT_DEPLOY_FEE,
+            max_tx_size: usize::MAX,
+            fee_calculator: Box::new(FlatFee::new(0)),
+            block_time_seconds: DEFAULT_BLOCK_TIME_SECONDS,
+            elapsed_seconds: 0,
+            snapshots: HashMap::new(),
+            blocks_per_epoch: u64::MAX,
+            epoch_hook: None,
+            genesis: contracts.clone(),
+            // instantiate two token contracts on the blockchain
+            contracts,
+        }
+    }
+
+    // a block time under 1 second would produce endless blocks, so it's treated as 1
+    pub fn with_block_time(mut self, block_time_seconds: u64) -> Blockchain {
+        self.block_time_seconds = block_time_seconds.max(1);
+        self
+    }
+
+    // an epoch of no blocks would never end, so it's rejected
+    pub fn with_blocks_per_epoch(mut self, blocks_per_epoch: u64) -> Result<Blockchain, Error> {
+        if blocks_per_epoch == 0 {
+            return Err(Error::ZeroEpochLength);
+        }
+        self.blocks_per_epoch = blocks_per_epoch;
+        Ok(self)
+    }
+
+    // the epoch the current block height falls in, counting from 0
+    pub fn current_epoch(&self) -> u64 {
+        self.block_height / self.blocks_per_epoch
+    }
+
+    // call `callback` with the new epoch every time a block starts one
+    pub fn on_epoch_change(&mut self, callback: Box<dyn FnMut(u64)>) {
+        self.epoch_hook = Some(callback);
+    }
+
+    pub fn with_max_tx_size(mut self, max_tx_size: usize) -> Blockchain {
+        self.max_tx_size = max_tx_size;
+        self
+    }
+
+    pub fn with_deploy_fee(mut self, deploy_fee: u64) -> Blockchain {
+        self.deploy_fee = deploy_fee;
+        self
+    }
+
+    pub fn with_fee_calculator(mut self, fee_calculator: Box<dyn FeeCalculator>) -> Blockchain {
+        self.fee_calculator = fee_calculator;
+        self
+    }
+
+    // the native amount a transaction pays up front: what the fee calculator charges,
+    // plus the deploy fee for a deploy
+    pub fn transaction_fee(&self, transaction: &Transaction) -> u64 {
+        let fee = self.fee_calculator.calculate(transaction, self);
+        if transaction.method == Method::Deploy {
+            fee.saturating_add(self.deploy_fee)
+        } else {
+            fee
+        }
+    }
+
+    // start the chain at `genesis_height` rather than 0. everything at or below it counts
+    // as final, since there are no blocks there to roll back.
+    pub fn with_genesis_height(mut self, genesis_height: u64) -> Blockchain {
+        self.genesis_height = genesis_height;
+        self.block_height = genesis_height;
+        self.snapshots.clear();
+        if self.archive {
+            self.snapshot();
+        }
+        self
+    }
+
+    pub fn genesis_height(&self) -> u64 {
+        self.genesis_height
+    }
+
+    pub fn with_genesis_nonce(mut self, genesis_nonce: u64) -> Blockchain {
+        self.genesis_nonce = genesis_nonce;
+        self
+    }
+
+    // a gap under 1 would reject even the next sequence, so it's treated as 1
+    pub fn with_max_sequence_gap(mut self, max_sequence_gap: u64) -> Blockchain {
+        self.max_sequence_gap = max_sequence_gap.max(1);
+        self
+    }
+
+    pub fn with_sequence_mode(mut self, sequence_mode: SequenceMode) -> Blockchain {
+        self.sequence_mode = sequence_mode;
+        self
+    }
+
+    pub fn with_routing_mode(mut self, routing_mode: RoutingMode) -> Blockchain {
+        self.routing_mode = routing_mode;
+        self
+    }
+
+    pub fn with_contract_destinations(mut self, allow: bool) -> Blockchain {
+        self.allow_contract_destination = allow;
+        self
+    }
+
+    pub fn with_base_fee(mut self, base_fee: u64, gas_target: u64) -> Blockchain {
+        self.base_fee = base_fee;
+        self.gas_target = gas_target;
+        self
+    }
+
+    pub fn current_base_fee(&self) -> u64 {
+        self.base_fee
+    }
+
+    pub fn with_min_gas_price(mut self, min_gas_price: u64) -> Blockchain {
+        self.min_gas_price = min_gas_price;
+        self
+    }
+
+    pub fn with_block_reward(mut self, coinbase: &str, block_reward: u64) -> Blockchain {
+        self.coinbase = coinbase.into();
+        self.block_reward = block_reward;
+        self
+    }
+
+    pub fn with_validators(mut self, validators: Vec<String>) -> Blockchain {
+        self.validators = validators;
+        self
+    }
+
+    // the validator that proposed the block at the current height, round robin. none
+    // without any validators.
+    pub fn current_proposer(&self) -> Option<&String> {
+        if self.validators.is_empty() {
+            return None;
+        }
+        let index = self.block_height % self.validators.len() as u64;
+        self.validators.get(index as usize)
+    }
+
+    pub fn with_confirmations_required(mut self, confirmations_required: u64) -> Blockchain {
+        self.confirmations_required = confirmations_required;
+        self
+    }
+
+    pub fn with_self_transfer_policy(mut self, policy: SelfTransferPolicy) -> Blockchain {
+        self.self_transfer_policy = policy;
+        self
+    }
+
+    pub fn with_archive(mut self, archive: bool) -> Blockchain {
+        self.set_archive(archive);
+        self
+    }
+
+    // start or stop keeping every block's balances. turning it on keeps the current block
+    // too, turning it off drops everything kept so far.
+    pub fn set_archive(&mut self, archive: bool) {
+        self.archive = archive;
+        if archive {
+            self.snapshot();
+        } else {
+            self.snapshots.clear();
+        }
+    }
+
+    fn snapshot(&mut self) {
+        let ledgers = self
+            .contracts
+            .iter()
+            .map(|c| (c.contract(), c.iter_balances().collect()))
+            .collect();
+        self.snapshots.insert(self.block_height, ledgers);
+    }
+
+    // the balance an address held after the block at `height`, if it was archived
+    pub fn balance_at(&self, contract: &str, address: &str, height: u64) -> Result<u64, Error> {
+        if height > self.block_height {
+            return Err(Error::BlockNotFound);
+        }
+        let ledger = self
+            .snapshots
+            .get(&height)
+            .ok_or(Error::NotArchived)?
+            .get(contract)
+            .ok_or(Error::ContractNotFound)?;
+        Ok(ledger.get(address).copied().unwrap_or_default())
+    }
+
+    pub fn with_volume_cap(mut self, max_volume: u64, window_blocks: u64) -> Blockchain {
+        self.volume_cap = Some((max_volume, window_blocks));
+        self
+    }
+
+    // whether the block at `height` has enough blocks on top of it to be final
+    pub fn is_final(&self, height: u64) -> bool {
+        self.block_height
+            .checked_sub(height)
+            .is_some_and(|confirmations| confirmations >= self.confirmations_required)
+    }
+
+    // undo every block above `height`, putting the chain back in the state it was in just
+    // before the block after it: sequences, native balances, the base fee, events and
+    // metrics included, so the undone transactions can be sent again. whatever happened
+    // outside of blocks before that block is kept, and whatever happened after is undone.
+    pub fn rollback_to_height(&mut self, height: u64) -> Result<(), Error> {
+        if height >= self.block_height {
+            return Ok(());
+        }
+        if height < self.genesis_height || self.is_final(height + 1) {
+            return Err(Error::CannotRollbackFinalized);
+        }
+
+        let index = self
+            .rollback_points
+            .iter()
+            .position(|point| point.block_height == height)
+            .ok_or(Error::CannotRollbackFinalized)?;
+        let point = self.rollback_points.remove(index);
+        self.restore(point);
+        Ok(())
+    }
+
+    // swap the implementation of a deployed contract, carrying its ledger over
+    pub fn upgrade_contract(
+        &mut self,
+        address: &str,
+        mut new_impl: Box<dyn TokenContract>,
+    ) -> Result<(), Error> {
+        if new_impl.contract() != address {
+            return Err(Error::ContractAddressMismatch);
+        }
+        let contract = self
+            .contracts
+            .iter_mut()
+            .find(|c| c.contract() == address)
+            .ok_or(Error::ContractNotFound)?;
+        new_impl.load_ledger(contract.balances_snapshot());
+        *contract = new_impl;
+        Ok(())
+    }
+
+    // mint to many addresses at once on behalf of `minter`, with a Mint event for each.
+    // like upgrade_contract this happens outside of any block.
+    pub fn batch_mint(
+        &mut self,
+        contract: &str,
+        minter: &str,
+        recipients: Vec<(String, u64)>,
+    ) -> Result<(), Error> {
+        let token = self
+            .contracts
+            .iter_mut()
+            .find(|c| c.contract() == contract)
+            .ok_or(Error::ContractNotFound)?;
+        let addresses: Vec<String> = recipients.iter().map(|(a, _)| a.clone()).collect();
+        let before = balances_with(token.as_ref(), &addresses);
+        token.batch_mint(minter, recipients.clone())?;
+        let after = balances_with(token.as_ref(), &addresses);
+
+        for (address, old, new) in diff_balances(&before, &after, &addresses) {
+            self.balance_changes.push(BalanceChange {
+                contract: contract.into(),
+                address,
+                old,
+                new,
+                height: self.block_height,
+            });
+        }
+        for (to, amount) in recipients {
+            self.emit(Event::Mint {
+                contract: contract.into(),
+                to,
+                amount,
+            });
+        }
+        Ok(())
+    }
+
+    // switch one of a contract's methods off or back on, on behalf of `caller`
+    pub fn set_method_enabled(
+        &mut self,
+        contract: &str,
+        caller: &str,
+        method: Method,
+        enabled: bool,
+    ) -> Result<(), Error> {
+        self.contracts
+            .iter_mut()
+            .find(|c| c.contract() == contract)
+            .ok_or(Error::ContractNotFound)?
+            .set_method_enabled(caller, method, enabled)
+    }
+
+    // the address a contract deployed by `deployer` with this nonce gets, CREATE style: the
+    // same inputs always give the same address
+    pub fn derive_contract_address(deployer: &str, nonce: u64) -> String {
+        let mut hasher = DefaultHasher::new();
+        deployer.hash(&mut hasher);
+        nonce.hash(&mut hasher);
+        format!("0x{:016x}", hasher.finish())
+    }
+
+    fn find_contract(&self, address: &str) -> Option<&dyn TokenContract> {
+        self.contracts
+            .iter()
+            .find(|c| c.contract() == address)
+            .map(|c| c.as_ref())
+    }
+
+    pub fn contract_kind(&self, address: &str) -> Option<ContractKind> {
+        self.find_contract(address).map(|c| c.kind())
+    }
+
+    // balances of many addresses at once, in the order they were asked for
+    pub fn balances_of(&self, contract: &str, addresses: &[String]) -> Result<Vec<u64>, Error> {
+        let contract = self
+            .find_contract(contract)
+            .ok_or(Error::ContractNotFound)?;
+        Ok(addresses
+            .iter()
+            .map(|a| contract.balance_of(a.clone()))
+            .collect())
+    }
+
+    // check the listed addresses hold exactly the expected balances. on a mismatch the error
+    // reports every address that's off, one per line and in the order given, like
+    // "addr2: expected 100, got 90". addresses that aren't listed aren't checked.
+    pub fn assert_balances(
+        &self,
+        contract: &str,
+        expected: &[(String, u64)],
+    ) -> Result<(), String> {
+        let token = self
+            .find_contract(contract)
+            .ok_or_else(|| format!("contract {} not found", contract))?;
+        let mut report = String::new();
+        for (address, balance) in expected {
+            let actual = token.balance_of(address.clone());
+            if actual != *balance {
+                let _ = writeln!(report, "{}: expected {}, got {}", address, balance, actual);
+            }
+        }
+        if report.is_empty() {
+            Ok(())
+        } else {
+            Err(report)
+        }
+    }
+
+    // addresses the chain tracks a sequence for: every one that has sent a transaction or
+    // opened an account, and not deleted it since
+    pub fn account_count(&self) -> usize {
+        self.accounts.len()
+    }
+
+    pub fn contract_count(&self) -> usize {
+        self.contracts.len()
+    }
+
+    pub fn contract_addresses(&self) -> Vec<String> {
+        self.contracts.iter().map(|c| c.contract()).collect()
+    }
+
+    // addresses holding a non-zero balance of a token, 0 if there's no such contract
+    pub fn total_accounts_with_balance(&self, contract: &str) -> usize {
+        self.find_contract(contract)
+            .map(|c| {
+                c.iter_balances()
+                    .filter(|(_, balance)| *balance > 0)
+                    .count()
+            })
+            .unwrap_or_default()
+    }
+
+    // the n largest holders of a token, by balance descending and then by address
+    pub fn top_holders(&self, contract: &str, n: usize) -> Result<Vec<(String, u64)>, Error> {
+        let contract = self
+            .find_contract(contract)
+            .ok_or(Error::ContractNotFound)?;
+        let mut holders: Vec<(String, u64)> = contract
+            .iter_balances()
+            .filter(|(_, balance)| *balance > 0)
+            .collect();
+        ranking::rank_by_balance(&mut holders);
+        holders.truncate(n);
+        Ok(holders)
+    }
+
+    // every contract `address` holds a non-zero balance of, and the balance, by contract
+    pub fn holdings(&self, address: &str) -> Vec<(String, u64)> {
+        let mut holdings: Vec<(String, u64)> = self
+            .contracts
+            .iter()
+            .map(|c| (c.contract(), c.balance_of(address.into())))
+            .filter(|(_, balance)| *balance > 0)
+            .collect();
+        holdings.sort();
+        holdings
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn blacklist(&mut self, address: &str) {
+        self.blacklist.insert(address.into());
+    }
+
+    pub fn unblacklist(&mut self, address: &str) {
+        self.blacklist.remove(address);
+    }
+
+    pub fn set_whitelist_mode(&mut self, whitelist_mode: bool) {
+        self.whitelist_mode = whitelist_mode;
+    }
+
+    pub fn whitelist(&mut self, address: &str) {
+        self.whitelist.insert(address.into());
+    }
+
+    pub fn unwhitelist(&mut self, address: &str) {
+        self.whitelist.remove(address);
+    }
+
+    pub fn set_default_sender(&mut self, address: &str) {
+        self.default_sender = Some(address.into());
+    }
+
+    // route the transaction to its contract, and fill in the sender and sequence of a
+    // `Transaction::from_default` transaction, which needs a default sender to come from
+    fn apply_defaults(&self, transaction: &mut Transaction) -> Result<(), Error> {
+        if self.routing_mode == RoutingMode::ByDestination && transaction.method.calls_contract() {
+            transaction.contract = transaction.destination.clone();
+        }
+        if !transaction.sender.is_empty() {
+            return Ok(());
+        }
+        let sender = self.default_sender.as_ref().ok_or(Error::MissingSender)?;
+        transaction.sender = sender.clone();
+        if transaction.sequence == 0 {
+            transaction.sequence = self.current_sequence(&transaction.sender) + 1;
+        }
+        Ok(())
+    }
+
+    pub fn native_balance_of(&self, address: &str) -> u64 {
+        self.native.get(address).copied().unwrap_or_default()
+    }
+
+    pub fn credit_native(&mut self, address: &str, amount: u64) {
+        let balance = self.native.entry(address.into()).or_default();
+        *balance = balance.saturating_add(amount);
+    }
+
+    // human readable dump of the whole chain state. every section is sorted by key
+    // (contracts stay in registration order), so identical states dump identically.
+    pub fn dump_state(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "block_height {}", self.block_height);
+        for (address, sequence) in sorted_entries(&self.accounts) {
+            let _ = writeln!(out, "sequence {} {}", address, sequence);
+        }
+        for (address, balance) in sorted_entries(&self.native) {
+            let _ = writeln!(out, "native {} {}", address, balance);
+        }
+        for contract in &self.contracts {
+            for (address, balance) in contract.balances_snapshot() {
+                let _ = writeln!(
+                    out,
+                    "balance {} {} {}",
+                    contract.contract(),
+                    address,
+                    balance
+                );
+            }
+        }
+        out
+    }
+
+    // last sequence used by an address. an address that never transacted starts at the
+    // genesis nonce, and a deleted one keeps the sequence it was deleted at.
+    fn current_sequence(&self, address: &str) -> u64 {
+        self.accounts
+            .get(address)
+            .or_else(|| self.retired_sequences.get(address))
+            .copied()
+            .unwrap_or(self.genesis_nonce)
+    }
+
+    // explicitly open an account. a new address starts at the genesis nonce, but an address
+    // that was deleted picks up the sequence it was deleted at: starting it over would make
+    // every transaction it ever sent valid again.
+    pub fn create_account(&mut self, address: &str) -> Result<(), Error> {
+        if self.accounts.contains_key(address) {
+            return Err(Error::AccountExists);
+        }
+        let sequence = self.current_sequence(address);
+        self.retired_sequences.remove(address);
+        self.accounts.insert(address.into(), sequence);
+        Ok(())
+    }
+
+    // close an account that holds nothing, natively or in any contract
+    pub fn delete_account(&mut self, address: &str) -> Result<(), Error> {
+        let sequence = *self.accounts.get(address).ok_or(Error::AccountNotFound)?;
+        if self.native_balance_of(address) > 0
+            || self
+                .contracts
+                .iter()
+                .any(|c| c.balance_of(address.into()) > 0)
+        {
+            return Err(Error::AccountNotEmpty);
+        }
+        self.accounts.remove(address);
+        self.retired_sequences.insert(address.into(), sequence);
+        Ok(())
+    }
+
+    fn check_sequence(&self, transaction: &Transaction) -> Result<(), Error> {
+        let current = self.current_sequence(&transaction.sender);
+        if transaction.sequence <= current {
+            // invalid, the transaction sequence must increase!
+            return Err(Error::BadTransactionSequence);
+        }
+        // skipping ahead is fine, but not so far a sender could burn through the sequence
+        // space, and not at all in strict mode
+        let max_gap = match self.sequence_mode {
+            SequenceMode::Monotonic | SequenceMode::Permissive => self.max_sequence_gap,
+            SequenceMode::Strict => 1,
+        };
+        if transaction.sequence > current.saturating_add(max_gap) {
+            return Err(Error::SequenceGapTooLarge);
+        }
+        Ok(())
+    }
+
+    // the lowest sequence `sender`'s next transaction may use without a resync. in every
+    // sequence mode that's the one right after its current sequence.
+    pub fn next_valid_sequence(&self, sender: &str) -> u64 {
+        self.current_sequence(sender).saturating_add(1)
+    }
+
+    pub fn export_sequences(&self) -> HashMap<String, u64> {
+        self.accounts
+            .iter()
+            .map(|(address, sequence)| (address.clone(), *sequence))
+            .collect()
+    }
+
+    // replace every account's sequence. no sequence may go backwards, including by leaving
+    // an existing account out, since that would reopen transactions it already sent.
+    pub fn import_sequences(&mut self, sequences: HashMap<String, u64>) -> Result<(), Error> {
+        let dropped = self
+            .accounts
+            .keys()
+            .any(|address| !sequences.contains_key(address));
+        let lowered = sequences
+            .iter()
+            .any(|(address, sequence)| *sequence < self.current_sequence(address));
+        if dropped || lowered {
+            return Err(Error::SequenceRegression);
+        }
+        self.accounts = sequences.into_iter().collect();
+        Ok(())
+    }
+
+    // noops using up every sequence after the sender's current one, up to and including
+    // `up_to_sequence`, to close a gap. none for a sender already at the last sequence.
+    pub fn generate_nonce_fillers(&self, sender: &str, up_to_sequence: u64) -> Vec<Transaction> {
+        let Some(next) = self.current_sequence(sender).checked_add(1) else {
+            return vec![];
+        };
+        (next..=up_to_sequence)
+            .map(|seq| Transaction::new(sender, 0, "", Method::Noop).with_seq(seq))
+            .collect()
+    }
+
+    // whether a transaction reuses a sequence the sender already spent, as opposed to
+    // skipping ahead
+    pub fn is_replay(&self, transaction: &Transaction) -> bool {
+        transaction.sequence <= self.current_sequence(&transaction.sender)
+    }
+
+    // commitment to the token state: every contract's ledger, and the height it was reached
+    // at. native balances and sequences aren't covered, since neither can be rebuilt from
+    // blocks (gas and failed transactions change them without sealing one).
+    pub fn state_root(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.block_height.hash(&mut hasher);
+        for contract in &self.contracts {
+            contract.contract().hash(&mut hasher);
+            contract.balances_snapshot().hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    // re-execute blocks on top of the current state. the transactions already passed
+    // validation when they were first included, so only their execution is repeated.
+    fn replay_blocks(&mut self, blocks: &[Block]) -> Result<(), Error> {
+        for block in blocks {
+            if block.transactions.is_empty() {
+                self.produce_block();
+            }
+            for transaction in &block.transactions {
+                self.execute(transaction.clone(), block.gas_used)?;
+            }
+        }
+        Ok(())
+    }
+
+    // a fresh chain in the state this one started out in, to replay blocks on, configured
+    // to execute them the same way
+    fn replica(&self) -> Blockchain {
+        let mut replica = Blockchain::new(self.genesis.clone())
+            .with_genesis_height(self.genesis_height)
+            .with_self_transfer_policy(self.self_transfer_policy)
+            .with_block_reward(&self.coinbase, self.block_reward)
+            .with_validators(self.validators.clone());
+        replica.volume_cap = self.volume_cap;
+        replica
+    }
+
+    // replay every block from genesis on a fresh chain, and check it ends up in the same
+    // state. changes made outside of blocks, like upgrading a contract, will fail this.
+    pub fn verify_deterministic(&self) -> bool {
+        let mut replica = self.replica();
+        replica.replay_blocks(&self.blocks).is_ok() && replica.state_root() == self.state_root()
+    }
+
+    // the contracts the chain started from, then every block it sealed, one line per
+    // transaction. see txlog for the format.
+    pub fn export_log(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "genesis\t{}", self.genesis_height);
+        for contract in &self.genesis {
+            let address = txlog::escape(&contract.contract());
+            let _ = writeln!(out, "contract\t{}", address);
+            for (holder, balance) in contract.balances_snapshot() {
+                let holder = txlog::escape(&holder);
+                let _ = writeln!(out, "balance\t{}\t{}\t{}", address, holder, balance);
+            }
+        }
+        for block in &self.blocks {
+            if block.transactions.is_empty() {
+                let _ = writeln!(out, "block\t{}", block.gas_used);
+            }
+            for transaction in &block.transactions {
+                let fields = txlog::format_transaction(transaction);
+                let _ = writeln!(out, "tx\t{}\t{}", block.gas_used, fields);
+            }
+        }
+        out
+    }
+
+    // rebuild a chain from an exported log by re-executing its blocks, and pick up every
+    // sender's sequence from them. contracts come back as plain BasicTokens holding their
+    // genesis balances, so hooks and the chain's own settings aren't carried over.
+    pub fn replay_log(log: &str) -> Result<Blockchain, Error> {
+        let mut genesis_height = 0;
+        let mut genesis: Vec<(String, Vec<(String, u64)>)> = Vec::new();
+        let mut blocks = Vec::new();
+        for (i, line) in log.lines().enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+
+            let parse_error = Error::ParseError { line: i + 1 };
+            let fields: Vec<&str> = line.split('\t').collect();
+            let (transactions, gas_used) = match fields.as_slice() {
+                ["genesis", height] => {
+                    genesis_height = height.parse().map_err(|_| parse_error)?;
+                    continue;
+                }
+                ["contract", address] => {
+                    let address = txlog::unescape(address).ok_or(parse_error)?;
+                    genesis.push((address, Vec::new()));
+                    continue;
+                }
+                ["balance", contract, holder, balance] => {
+                    let contract = txlog::unescape(contract).ok_or(parse_error)?;
+                    let holder = txlog::unescape(holder).ok_or(parse_error)?;
+                    let balance = balance.parse().map_err(|_| parse_error)?;
+                    match genesis.last_mut() {
+                        Some((address, balances)) if *address == contract => {
+                            balances.push((holder, balance))
+                        }
+                        _ => return Err(parse_error),
+                    }
+                    continue;
+                }
+                ["block", gas_used] => (vec![], gas_used),
+                ["tx", gas_used, transaction @ ..] => {
+                    let transaction = txlog::parse_transaction(transaction).ok_or(parse_error)?;
+                    (vec![transaction], gas_used)
+                }
+                _ => return Err(parse_error),
+            };
+            blocks.push(Block {
+                height: genesis_height + blocks.len() as u64 + 1,
+                transactions,
+                gas_used: gas_used.parse().map_err(|_| parse_error)?,
+                coinbase: None,
+            });
+        }
+
+        let contracts = genesis
+            .into_iter()
+            .map(|(address, balances)| {
+                BasicToken::from_balances(address, balances)
+                    .map(|token| Box::new(token) as Box<dyn TokenContract>)
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        let mut replica = Blockchain::new(contracts).with_genesis_height(genesis_height);
+        replica.replay_blocks(&blocks)?;
+        for transaction in blocks.iter().flat_map(|b| &b.transactions) {
+            replica
+                .accounts
+                .insert(transaction.sender.clone(), transaction.sequence);
+        }
+        Ok(replica)
+    }
+
+    pub fn validate_transaction_sequence(
+        &mut self,
+        transaction: &Transaction,
+    ) -> Result<(), Error> {
+        self.check_sequence(transaction)?;
+        // update the sequence
+        self.accounts
+            .insert(transaction.sender.clone(), transaction.sequence);
+        Ok(())
+    }
+
+    // every check a transaction must pass before it executes, without changing any state.
+    // when several fail the first group wins, in this order:
+    //   1. chain level: blacklisting, malformed transactions and the validity window
+    //   2. existence: the contract being called, or the one a deploy would create
+    //   3. authorization: the signature
+    //   4. the sequence
+    //   5. the fee, and for a metered transaction the base fee, gas price and gas
+    // and anything the execution itself fails on, like the balance, comes after all of them.
+    // none of these use up the sequence of a transaction they reject. `gas_payer` is who
+    // pays for the gas of a metered transaction, None for one that isn't metered.
+    fn check_validity(
+        &self,
+        transaction: &Transaction,
+        gas_payer: Option<&str>,
+    ) -> Result<(), Error> {
+        // 1. reject a transaction the chain won't take at all, whatever it calls
+        if self.blacklist.contains(&transaction.sender)
+            || (transaction.method.is_transfer()
+                && self.blacklist.contains(&transaction.destination))
+        {
+            return Err(Error::Blacklisted);
+        }
+        if self.whitelist_mode && !self.whitelist.contains(&transaction.sender) {
+            return Err(Error::NotWhitelisted);
+        }
+        if transaction
+            .memo
+            .as_ref()
+            .is_some_and(|m| m.len() > MAX_MEMO_LEN)
+        {
+            return Err(Error::MemoTooLong);
+        }
+        if transaction.size() > self.max_tx_size {
+            return Err(Error::TransactionTooLarge);
+        }
+        if let Some(valid_until) = transaction.valid_until {
+            if valid_until < self.block_height {
+                return Err(Error::TransactionExpired);
+            }
+        }
+        if let Some(valid_from) = transaction.valid_from {
+            if valid_from > self.block_height {
+                return Err(Error::TransactionNotYetValid);
+            }
+        }
+        // a contract's address isn't an account, it can't send, and only receives if allowed
+        if self.find_contract(&transaction.sender).is_some() {
+            return Err(Error::InvalidSender);
+        }
+        if transaction.method.is_transfer()
+            && !self.allow_contract_destination
+            && self.routing_mode == RoutingMode::ByContractField
+            && self.find_contract(&transaction.destination).is_some()
+        {
+            return Err(Error::InvalidDestination);
+        }
+        if transaction.method.is_transfer()
+            && transaction.sender == transaction.destination
+            && self.self_transfer_policy == SelfTransferPolicy::Deny
+        {
+            return Err(Error::SelfTransfer);
+        }
+
+        // 2. the contract has to exist, unless nothing gets called, and the one a deploy
+        // creates can't exist yet
+        if transaction.method.calls_contract()
+            && self.find_contract(&transaction.contract).is_none()
+        {
+            return Err(Error::ContractNotFound);
+        }
+        if transaction.method == Method::Deploy
+            && self
+                .find_contract(&Blockchain::derive_contract_address(
+                    &transaction.sender,
+                    transaction.sequence,
+                ))
+                .is_some()
+        {
+            return Err(Error::AccountExists);
+        }
+
+        // 3. validate the signature (this is important to authenticate the transaction)
+        // (for brevity, unsigned transactions are still just assumed to be signed)
+        if let Some(signature) = &transaction.signature {
+            if !verify_signature(transaction, signature) {
+                return Err(Error::InvalidSignature);
+            }
+        }
+
+        // 4. validate the transaction is not a replay.  if we don't do this, then bad things can happen.
+        // (unless the chain was told to let it through, see validate_transaction)
+        if self.sequence_mode != SequenceMode::Permissive {
+            self.check_sequence(transaction)?;
+        }
+
+        // 5. a metered transaction that can't afford the base fee never gets anywhere near
+        // a block
+        if gas_payer.is_some() {
+            if transaction.max_fee < self.base_fee {
+                return Err(Error::FeeTooLow);
+            }
+            if transaction.gas_price < self.min_gas_price {
+                return Err(Error::GasPriceTooLow);
+            }
+        }
+        // everything paid up front is paid by whoever pays for the gas
+        let payer = gas_payer.unwrap_or(&transaction.sender);
+        if self.native_balance_of(payer) < self.upfront_cost(transaction, gas_payer.is_some())? {
+            return Err(Error::InsufficientFeeBalance);
+        }
+        Ok(())
+    }
+
+    // the native amount charged before a transaction executes: the fee and the tip, and for
+    // a metered transaction the whole gas limit and the base fee too. a cost too large to
+    // count can't be afforded.
+    fn upfront_cost(&self, transaction: &Transaction, metered: bool) -> Result<u64, Error> {
+        let fee = self
+            .transaction_fee(transaction)
+            .checked_add(transaction.tip);
+        let cost = if metered {
+            transaction
+                .gas_limit
+                .checked_mul(transaction.gas_price)
+                .and_then(|gas| gas.checked_add(self.base_fee))
+                .and_then(|prepaid| prepaid.checked_add(fee?))
+        } else {
+            fee
+        };
+        cost.ok_or(Error::InsufficientFeeBalance)
+    }
+
+    // validate a transaction and consume its sequence. its upfront cost is charged here, to
+    // whoever pays for the gas of a metered transaction, rather than when it executes, so
+    // replaying its block doesn't charge it again. the tip is only paid out once the
+    // transaction is sealed into a block, and a transaction that fails burns it along with
+    // the fee.
+    fn validate_transaction(
+        &mut self,
+        transaction: &Transaction,
+        gas_payer: Option<&str>,
+    ) -> Result<(), Error> {
+        self.check_validity(transaction, gas_payer)?;
+        if self.sequence_mode == SequenceMode::Permissive
+            && self.check_sequence(transaction).is_err()
+        {
+            self.emit(Event::SequenceResync {
+                address: transaction.sender.clone(),
+                from: self.current_sequence(&transaction.sender),
+                to: transaction.sequence,
+            });
+        }
+        self.accounts
+            .insert(transaction.sender.clone(), transaction.sequence);
+        let cost = self.upfront_cost(transaction, gas_payer.is_some())?;
+        if cost > 0 {
+            let payer = gas_payer.unwrap_or(&transaction.sender);
+            let balance = self.native_balance_of(payer);
+            self.native.insert(payer.into(), balance - cost);
+        }
+        Ok(())
+    }
+
+    // the first error processing `transaction` would run into before it executes, if any.
+    // never changes any state.
+    pub fn check_transaction(&self, transaction: &Transaction) -> Result<(), Error> {
+        let mut transaction = transaction.clone();
+        self.apply_defaults(&mut transaction)?;
+        self.check_validity(&transaction, None)
+    }
+
+    // run a transaction against a copy of its contract, failing wherever processing it
+    // would. never changes any state. gas isn't metered.
+    pub fn simulate(&self, transaction: &Transaction) -> Result<Simulation, Error> {
+        let mut transaction = transaction.clone();
+        self.apply_defaults(&mut transaction)?;
+        self.check_validity(&transaction, None)?;
+        if !transaction.method.calls_contract() {
+            return Ok(Simulation {
+                value: 0,
+                transferred: None,
+                skipped: false,
+                changes: vec![],
+            });
+        }
+
+        let self_transfer_noop = transaction.method.is_transfer()
+            && transaction.sender == transaction.destination
+            && self.self_transfer_policy == SelfTransferPolicy::NoOp;
+        let mut contract = self
+            .find_contract(&transaction.contract)
+            .ok_or(Error::ContractNotFound)?
+            .box_clone();
+        contract.set_block_height(self.block_height + 1);
+        let touched = [transaction.sender.clone(), transaction.destination.clone()];
+        let before = balances_with(contract.as_ref(), &touched);
+        let (value, transferred, skipped) =
+            call_method(contract.as_mut(), &transaction, self_transfer_noop)?;
+        if let (Some(cap), Some(amount)) = (self.volume_cap, transferred) {
+            let recent = self
+                .outgoing_volume
+                .get(&(transaction.contract.clone(), transaction.sender.clone()))
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+            check_volume_cap(recent, cap, self.block_height + 1, amount)?;
+        }
+        let after = balances_with(contract.as_ref(), &touched);
+
+        let changes = diff_balances(&before, &after, &touched)
+            .into_iter()
+            .map(|(address, old, new)| BalanceChange {
+                contract: transaction.contract.clone(),
+                address,
+                old,
+                new,
+                height: self.block_height + 1,
+            })
+            .collect();
+        Ok(Simulation {
+            value,
+            transferred,
+            skipped,
+            changes,
+        })
+    }
+
+    // like simulate, returning the value and the events processing the transaction would
+    // emit
+    pub fn simulate_with_events(
+        &self,
+        transaction: &Transaction,
+    ) -> Result<(u64, Vec<Event>), Error> {
+        let mut transaction = transaction.clone();
+        self.apply_defaults(&mut transaction)?;
+        let simulation = self.simulate(&transaction)?;
+        let events = match (
+            simulation.transferred,
+            self.find_contract(&transaction.contract),
+        ) {
+            (Some(amount), Some(contract)) => vec![transfer_event(contract, &transaction, amount)],
+            _ => vec![],
+        };
+        Ok((simulation.value, events))
+    }
+
+    // narrate what processing a transaction would do, like
+    // "addr1 sends 100 USDC to addr2; addr1 balance 1000→900; addr2 balance 0→100"
+    pub fn explain(&self, transaction: &Transaction) -> String {
+        let mut transaction = transaction.clone();
+        if let Err(e) = self.apply_defaults(&mut transaction) {
+            return format!(
+                "a {} without a sender would fail with {:?}",
+                transaction.method.as_str(),
+                e
+            );
+        }
+        let (sender, contract) = (&transaction.sender, &transaction.contract);
+
+        let simulation = match self.simulate(&transaction) {
+            Ok(simulation) => simulation,
+            Err(e) => {
+                return format!(
+                    "{}'s {} would fail with {:?}",
+                    sender,
+                    transaction.method.as_str(),
+                    e
+                )
+            }
+        };
+        let mut explanation = match (transaction.method, simulation.transferred) {
+            (Method::BalanceOf, _) => format!("{} holds {} {}", sender, simulation.value, contract),
+            (Method::Noop, _) => format!("{} uses up sequence {}", sender, transaction.sequence),
+            (Method::Deploy, _) => format!(
+                "{} deploys {} at {}",
+                sender,
+                transaction.deploy.clone().unwrap_or_default().name,
+                Blockchain::derive_contract_address(sender, transaction.sequence)
+            ),
+            (_, Some(amount)) => format!(
+                "{} sends {} {} to {}",
+                sender, amount, contract, transaction.destination
+            ),
+            (method, None) => format!("{}'s {} is skipped", sender, method.as_str()),
+        };
+        for change in simulation.changes {
+            let _ = write!(
+                explanation,
+                "; {} balance {}→{}",
+                change.address, change.old, change.new
+            );
+        }
+        explanation
+    }
+
+    pub fn process_transaction(&mut self, mut transaction: Transaction) -> Result<u64, Error> {
+        let point = self.rollback_point();
+
+        // first, validate the transaction, then run it
+        let result = self
+            .apply_defaults(&mut transaction)
+            .and_then(|_| self.validate_transaction(&transaction, None))
+            .and_then(|_| self.execute(transaction, 0));
+        self.metrics.record(&result);
+        self.keep_rollback_point(point);
+        result
+    }
+
+    // process a transaction, receipting it. an exact duplicate of a transaction that
+    // already executed gets its receipt back, marked cached, without running again or
+    // being checked for a replayed sequence.
+    pub fn process_idempotent(
+        &mut self,
+        mut transaction: Transaction,
+    ) -> Result<TransactionReceipt, Error> {
+        self.apply_defaults(&mut transaction)?;
+        if let Some(receipt) = self.receipt(transaction.hash()) {
+            return Ok(TransactionReceipt {
+                cached: true,
+                ..receipt.clone()
+            });
+        }
+        self.process_transaction(transaction)?;
+        Ok(self.receipts[self.receipts.len() - 1].clone())
+    }
+
+    // process a transaction that pays for its execution with gas.
+    // the sender is pre-charged gas_limit * gas_price in native balance, and refunded
+    // for whatever gas was not used. the base fee is burnt on top. running out of gas
+    // leaves the contracts untouched but still charges the full limit.
+    pub fn process(&mut self, mut transaction: Transaction) -> Result<u64, Error> {
+        let point = self.rollback_point();
+        let result = self.apply_defaults(&mut transaction).and_then(|_| {
+            let payer = transaction.sender.clone();
+            self.process_with_gas(transaction, &payer)
+        });
+        self.metrics.record(&result);
+        self.keep_rollback_point(point);
+        result
+    }
+
+    // process a metered transaction whose gas, fee and tip the relayer pays for, natively,
+    // while the tokens still move on the sender's behalf
+    pub fn relay(&mut self, mut transaction: Transaction, relayer: &str) -> Result<u64, Error> {
+        let point = self.rollback_point();
+        let result = self
+            .apply_defaults(&mut transaction)
+            .and_then(|_| self.process_with_gas(transaction, relayer));
+        self.metrics.record(&result);
+        self.keep_rollback_point(point);
+        result
+    }
+
+    fn process_with_gas(&mut self, transaction: Transaction, payer: &str) -> Result<u64, Error> {
+        // everything, whether the payer can cover the gas included, is checked before
+        // anything is charged. the whole gas limit is charged along with the fee, and the
+        // base fee is burnt, whatever happens to the transaction.
+        self.validate_transaction(&transaction, Some(payer))?;
+
+        let gas_used = self.gas_used(&transaction);
+        if gas_used > transaction.gas_limit {
+            return Err(Error::OutOfGas);
+        }
+
+        // refund the unused gas, whether or not the execution itself succeeded
+        let refund = (transaction.gas_limit - gas_used) * transaction.gas_price;
+        let result = self.execute(transaction, gas_used);
+        self.credit_native(payer, refund);
+        result
+    }
+
+    // gas a transaction would use if executed against the current state
+    pub fn gas_used(&self, transaction: &Transaction) -> u64 {
+        match transaction.method {
+            Method::BalanceOf => GAS_BASE + GAS_BALANCE_OF,
+            Method::Noop => GAS_BASE,
+            Method::Deploy => GAS_BASE + GAS_DEPLOY,
+            _ => {
+                let is_new_account = self
+                    .find_contract(&transaction.contract)
+                    .map(|c| c.balance_of(transaction.destination.clone()) == 0)
+                    .unwrap_or_default();
+                if is_new_account {
+                    GAS_BASE + GAS_TRANSFER + GAS_NEW_ACCOUNT
+                } else {
+                    GAS_BASE + GAS_TRANSFER
+                }
+            }
+        }
+    }
+
+    // create the token a deploy transaction describes. its address goes in the receipt.
+    fn deploy(&mut self, transaction: Transaction, gas_used: u64) -> Result<u64, Error> {
+        let address =
+            Blockchain::derive_contract_address(&transaction.sender, transaction.sequence);
+        let params = transaction.deploy.clone().unwrap_or_default();
+        let token =
+            BasicToken::from_balances(address.clone(), params.balances)?.with_name(&params.name);
+        self.contracts.push(Box::new(token));
+
+        self.seal_block(vec![transaction.clone()], gas_used);
+        self.push_receipt(transaction, Some(address), 0, false);
+        Ok(0)
+    }
+
+    // run a validated transaction and seal it into a block. gas_used is what a metered
+    // transaction was charged for, and 0 otherwise.
+    fn execute(&mut self, transaction: Transaction, gas_used: u64) -> Result<u64, Error> {
+        if transaction.method == Method::Noop {
+            self.seal_block(vec![transaction.clone()], gas_used);
+            self.push_receipt(transaction, None, 0, false);
+            return Ok(0);
+        }
+        if transaction.method == Method::Deploy {
+            return self.deploy(transaction, gas_used);
+        }
+
+        let self_transfer_noop = transaction.method.is_transfer()
+            && transaction.sender == transaction.destination
+            && self.self_transfer_policy == SelfTransferPolicy::NoOp;
+
+        // try to locate a contract
+        let contract = self
+            .contracts
+            .iter_mut()
+            .find(|c| c.contract() == transaction.contract)
+            .ok_or(Error::ContractNotFound)?;
+
+        contract.set_block_height(self.block_height + 1);
+        let touched = [transaction.sender.clone(), transaction.destination.clone()];
+        let before = balances_with(contract.as_ref(), &touched);
+        // the amount moved is only known once the method has run, so keep a copy to put
+        // back if it turns out to be over the cap
+        let backup = self.volume_cap.map(|_| contract.box_clone());
+        let (value, transferred, skipped) =
+            call_method(contract.as_mut(), &transaction, self_transfer_noop)?;
+
+        let contract_address = contract.contract();
+        if let (Some(cap), Some(amount)) = (self.volume_cap, transferred) {
+            let height = self.block_height + 1;
+            let recent = self
+                .outgoing_volume
+                .entry((contract_address.clone(), transaction.sender.clone()))
+                .or_default();
+            if let Err(e) = check_volume_cap(recent, cap, height, amount) {
+                if let Some(backup) = backup {
+                    *contract = backup;
+                }
+                return Err(e);
+            }
+            let (_, window_blocks) = cap;
+            recent.retain(|(h, _)| h.saturating_add(window_blocks) > height);
+            recent.push((height, amount));
+        }
+        let after = balances_with(contract.as_ref(), &touched);
+        let event =
+            transferred.map(|amount| transfer_event(contract.as_ref(), &transaction, amount));
+        // what actually moved: what the destination received, or what the sender burnt
+        let moved = match transferred {
+            Some(_) if contract.burn_address().as_ref() == Some(&transaction.destination) => {
+                before[&transaction.sender].saturating_sub(after[&transaction.sender])
+            }
+            Some(_) => {
+                after[&transaction.destination].saturating_sub(before[&transaction.destination])
+            }
+            None => 0,
+        };
+
+        // every executed transaction seals a block
+        self.seal_block(vec![transaction.clone()], gas_used);
+
+        // record the balance of every address this block changed
+        for (address, old, new) in diff_balances(&before, &after, &touched) {
+            self.balance_history
+                .entry((contract_address.clone(), address.clone()))
+                .or_default()
+                .push((self.block_height, new));
+            self.balance_changes.push(BalanceChange {
+                contract: contract_address.clone(),
+                address,
+                old,
+                new,
+                height: self.block_height,
+            });
+        }
+
+        if let Some(event) = event {
+            self.emit(event);
+        }
+        self.metrics.volume = self.metrics.volume.saturating_add(moved);
+        self.push_receipt(transaction, Some(contract_address), value, skipped);
+
+        Ok(value)
+    }
+
+    // receipt a transaction executed in the block just sealed
+    fn push_receipt(
+        &mut self,
+        transaction: Transaction,
+        contract: Option<String>,
+        value: u64,
+        skipped: bool,
+    ) {
+        self.receipts.push(TransactionReceipt {
+            hash: transaction.hash(),
+            sender: transaction.sender,
+            sequence: transaction.sequence,
+            block_height: self.block_height,
+            method: transaction.method,
+            contract,
+            value,
+            memo: transaction.memo,
+            category: transaction.category,
+            skipped,
+            cached: false,
+        });
+    }
+
+    fn seal_block(&mut self, transactions: Vec<Transaction>, gas_used: u64) {
+        let epoch = self.current_epoch();
+        self.block_height += 1;
+
+        // move the base fee towards the gas target: up after a block that used more gas
+        // than the target, down after one that used less, like an empty one. a block with
+        // an unmetered transaction in it says nothing about demand, so it leaves the base
+        // fee alone.
+        if self.gas_target > 0 && (gas_used > 0 || transactions.is_empty()) {
+            let delta = (self.base_fee as u128 * gas_used.abs_diff(self.gas_target) as u128
+                / self.gas_target as u128
+                / BASE_FEE_CHANGE_DENOMINATOR as u128) as u64;
+            if gas_used > self.gas_target {
+                self.base_fee = self.base_fee.saturating_add(delta.max(1));
+            } else {
+                self.base_fee -= delta;
+            }
+        }
+
+        // mint the block reward, and pay the tips of the transactions in it, to the block's
+        // proposer, or the coinbase without validators. with neither the tips are burnt.
+        let recipient = self
+            .current_proposer()
+            .cloned()
+            .or_else(|| Some(self.coinbase.clone()).filter(|c| !c.is_empty()));
+        let paid = transactions
+            .iter()
+            .fold(self.block_reward, |paid, t| paid.saturating_add(t.tip));
+        let coinbase = match recipient {
+            Some(recipient) if paid > 0 => {
+                self.credit_native(&recipient, paid);
+                Some((recipient, paid))
+            }
+            _ => None,
+        };
+
+        self.blocks.push(Block {
+            height: self.block_height,
+            transactions,
+            gas_used,
+            coinbase,
+        });
+        if self.archive {
+            self.snapshot();
+        }
+        let new_epoch = self.current_epoch();
+        if new_epoch != epoch {
+            if let Some(hook) = &mut self.epoch_hook {
+                hook(new_epoch);
+            }
+        }
+    }
+
+    fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            block_height: self.block_height,
+            contracts: self.contracts.clone(),
+            accounts: self.accounts.clone(),
+            native: self.native.clone(),
+            balance_history: self.balance_history.clone(),
+            events: self.events.len(),
+            receipts: self.receipts.len(),
+            balance_changes: self.balance_changes.len(),
+            blocks: self.blocks.len(),
+            base_fee: self.base_fee,
+            outgoing_volume: self.outgoing_volume.clone(),
+            metrics: self.metrics.clone(),
+        }
+    }
+
+    fn restore(&mut self, checkpoint: Checkpoint) {
+        self.block_height = checkpoint.block_height;
+        self.contracts = checkpoint.contracts;
+        self.accounts = checkpoint.accounts;
+        self.native = checkpoint.native;
+        self.balance_history = checkpoint.balance_history;
+        self.events.truncate(checkpoint.events);
+        for indexes in self.event_topics.values_mut() {
+            indexes.retain(|i| *i < checkpoint.events);
+        }
+        self.event_topics.retain(|_, indexes| !indexes.is_empty());
+        self.receipts.truncate(checkpoint.receipts);
+        self.balance_changes.truncate(checkpoint.balance_changes);
+        self.blocks.truncate(checkpoint.blocks);
+        self.base_fee = checkpoint.base_fee;
+        self.outgoing_volume = checkpoint.outgoing_volume;
+        self.metrics = checkpoint.metrics;
+        self.snapshots.retain(|h, _| *h <= checkpoint.block_height);
+        self.rollback_points
+            .retain(|point| point.block_height < checkpoint.block_height);
+    }
+
+    // the state to roll back to if what comes next seals a block, only kept when blocks
+    // can be rolled back at all
+    fn rollback_point(&self) -> Option<Checkpoint> {
+        (self.confirmations_required > 0).then(|| self.checkpoint())
+    }
+
+    // keep the point taken before a block was sealed, and let go of every point before a
+    // block that's final by now
+    fn keep_rollback_point(&mut self, point: Option<Checkpoint>) {
+        if let Some(point) = point.filter(|p| p.block_height < self.block_height) {
+            self.rollback_points.push(point);
+        }
+        let (height, confirmations) = (self.block_height, self.confirmations_required);
+        self.rollback_points
+            .retain(|point| point.block_height.saturating_add(confirmations) >= height);
+    }
+
+    // process every transaction, or none of them: the first failure undoes the whole
+    // batch, across every contract it touched, and is returned. subscribers only hear
+    // about the batch's events once all of it has succeeded.
+    pub fn process_atomic(&mut self, transactions: Vec<Transaction>) -> Result<Vec<u64>, Error> {
+        let checkpoint = self.checkpoint();
+        let first_event = checkpoint.events;
+        let mut subscriptions = std::mem::take(&mut self.subscriptions);
+
+        let result: Result<Vec<u64>, Error> = transactions
+            .into_iter()
+            .map(|transaction| self.process_transaction(transaction))
+            .collect();
+
+        if result.is_err() {
+            // the metrics still count what was tried
+            let metrics = std::mem::take(&mut self.metrics);
+            self.restore(checkpoint);
+            self.metrics = metrics;
+        } else {
+            for event in &self.events[first_event..] {
+                for subscription in &mut subscriptions {
+                    if subscription.contract == event.contract() {
+                        (subscription.callback)(event);
+                    }
+                }
+            }
+        }
+        self.subscriptions = subscriptions;
+        result
+    }
+
+    // seal a block with no transactions in it, returning its height
+    pub fn produce_block(&mut self) -> u64 {
+        let point = self.rollback_point();
+        self.seal_block(vec![], 0);
+        self.keep_rollback_point(point);
+        self.block_height
+    }
+
+    // let `seconds` of simulated time pass, producing an empty block for every whole block
+    // time. what's left of a block time carries over to the next call. returns how many
+    // blocks were produced.
+    pub fn advance_time(&mut self, seconds: u64) -> u64 {
+        self.elapsed_seconds = self.elapsed_seconds.saturating_add(seconds);
+        let blocks = self.elapsed_seconds / self.block_time_seconds;
+        self.elapsed_seconds %= self.block_time_seconds;
+        for _ in 0..blocks {
+            self.produce_block();
+        }
+        blocks
+    }
+
+    pub fn blocks(&self) -> &[Block] {
+        &self.blocks
+    }
+
+    // the random beacon of the latest block, 0 before the first one
+    pub fn current_randomness(&self) -> u64 {
+        self.blocks
+            .last()
+            .map(|b| b.random_beacon())
+            .unwrap_or_default()
+    }
+
+    // log an event, calling every subscriber to its contract in the order they subscribed
+    fn emit(&mut self, event: Event) {
+        for subscription in &mut self.subscriptions {
+            if subscription.contract == event.contract() {
+                (subscription.callback)(&event);
+            }
+        }
+        let index = self.events.len();
+        for topic in event.topics() {
+            let indexes = self.event_topics.entry(topic.into()).or_default();
+            // a self-transfer lists the same address twice
+            if indexes.last() != Some(&index) {
+                indexes.push(index);
+            }
+        }
+        self.events.push(event);
+    }
+
+    // call `callback` with every event `contract` emits from now on
+    pub fn subscribe(
+        &mut self,
+        contract: &str,
+        callback: Box<dyn FnMut(&Event)>,
+    ) -> SubscriptionId {
+        let id = SubscriptionId(self.next_subscription_id);
+        self.next_subscription_id += 1;
+        self.subscriptions.push(Subscription {
+            id,
+            contract: contract.into(),
+            callback,
+        });
+        id
+    }
+
+    pub fn unsubscribe(&mut self, id: SubscriptionId) {
+        self.subscriptions.retain(|s| s.id != id);
+    }
+
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    // every event with `topic` among its topics, in the order emitted. looked up in an
+    // index, so it doesn't scan the whole log.
+    pub fn events_by_topic(&self, topic: &str) -> Vec<&Event> {
+        self.event_topics
+            .get(topic)
+            .map(|indexes| indexes.iter().map(|i| &self.events[*i]).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn receipts(&self) -> &[TransactionReceipt] {
+        &self.receipts
+    }
+
+    pub fn receipt(&self, hash: u64) -> Option<&TransactionReceipt> {
+        self.receipts.iter().find(|r| r.hash == hash)
+    }
+
+    // every balance change so far, across all contracts, oldest first
+    pub fn balance_changes(&self) -> Vec<BalanceChange> {
+        self.balance_changes.clone()
+    }
+
+    // receipts of the transactions `address` sent with this category, oldest first
+    pub fn history_by_category(&self, address: &str, category: &str) -> Vec<&TransactionReceipt> {
+        self.receipts
+            .iter()
+            .filter(|r| r.sender == address && r.category.as_deref() == Some(category))
+            .collect()
+    }
+
+    // (block_height, balance) after every block that changed the balance of an address
+    pub fn balance_history(&self, contract: &str, address: &str) -> Vec<(u64, u64)> {
+        self.balance_history
+            .get(&(contract.to_string(), address.to_string()))
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+fn test_blockchain() -> Result<(), Error> {
+    println!("This is an example blockchain.");
+    let mut blockchain = Blockchain::new(vec![
+        Box::new(BasicToken::new(
+            "USDC".into(),
+            vec!["addr1".into(), "addr2".into()],
+            1000,
+        )),
+        Box::new(BasicToken::new(
+            "WBTC".into(),
+            vec!["addr3".into(), "addr4".into()],
+            1000,
+        )),
+    ]);
+
+    let addr1_bal = blockchain
+        .process_transaction(Transaction::new("addr1", 0, "USDC", Method::BalanceOf).with_seq(1))?;
+
+    let addr2_bal = blockchain
+        .process_transaction(Transaction::new("addr2", 0, "USDC", Method::BalanceOf).with_seq(1))?;
+
+    // initial balances of addresses are 1000
+    assert!(addr1_bal == 1000);
+    assert!(addr2_bal == 1000);
+
+    // repeating a transaction is an error.
+    let iserr = blockchain
+        .process_transaction(Transaction::new("addr1", 0, "USDC", Method::BalanceOf).with_seq(1));
+    assert!(iserr.is_err());
+    assert!(iserr.err().unwrap() == Error::BadTransactionSequence);
+
+    // test sending 100 USDC from addr1 to addr2 (increment sequence to 2)
+    let _ = blockchain.process_transaction(
+        Transaction::new("addr1", 100, "USDC", Method::Transfer)
+            .with_seq(2)
+            .with_destination("addr2"),
+    )?;
+
+    // now lookup the balances
+    let addr1_bal = blockchain
+        .process_transaction(Transaction::new("addr1", 0, "USDC", Method::BalanceOf).with_seq(3))?;
+
+    let addr2_bal = blockchain
+        .process_transaction(Transaction::new("addr2", 0, "USDC", Method::BalanceOf).with_seq(3))?;
+
+    // balances changed accordingly
+    assert!(addr1_bal == 900);
+    assert!(addr2_bal == 1100);
+
+    Ok(())
+}
+
+fn test_gas() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into(), "addr2".into()],
+        1000,
+    ))]);
+    blockchain.credit_native("addr1", 1_000_000);
+
+    // a transfer to an existing account uses less gas than its limit, and the rest is refunded
+    blockchain.process(
+        Transaction::new("addr1", 100, "USDC", Method::Transfer)
+            .with_seq(1)
+            .with_destination("addr2")
+            .with_gas(50_000, 10),
+    )?;
+    assert!(blockchain.native_balance_of("addr1") == 1_000_000 - (GAS_BASE + GAS_TRANSFER) * 10);
+
+    // creating a new account costs more than the limit: the transfer is reverted
+    // but the full limit is still charged
+    let before = blockchain.native_balance_of("addr1");
+    let r = blockchain.process(
+        Transaction::new("addr1", 100, "USDC", Method::Transfer)
+            .with_seq(2)
+            .with_destination("addr5")
+            .with_gas(30_000, 10),
+    );
+    assert!(r == Err(Error::OutOfGas));
+    assert!(blockchain.native_balance_of("addr1") == before - 30_000 * 10);
+
+    let addr5_bal = blockchain
+        .process_transaction(Transaction::new("addr5", 0, "USDC", Method::BalanceOf).with_seq(1))?;
+    let addr1_bal = blockchain
+        .process_transaction(Transaction::new("addr1", 0, "USDC", Method::BalanceOf).with_seq(3))?;
+    assert!(addr5_bal == 0);
+    assert!(addr1_bal == 900);
+
+    // a sender that can't cover the limit is rejected up front, without using its sequence
+    let balance_of = Transaction::new("addr2", 0, "USDC", Method::BalanceOf)
+        .with_seq(1)
+        .with_gas(50_000, 10);
+    let r = blockchain.process(balance_of.clone());
+    assert!(r == Err(Error::InsufficientFeeBalance));
+    let r = blockchain.process(balance_of.clone().with_gas(u64::MAX, 2));
+    assert!(r == Err(Error::InsufficientFeeBalance));
+    blockchain.credit_native("addr2", 500_000);
+    assert!(blockchain.process(balance_of)? == 1100);
+
+    Ok(())
+}
+
+fn test_dump_state() -> Result<(), Error> {
+    let build = || -> Result<Blockchain, Error> {
+        let addresses = (0..20).map(|i| format!("addr{}", i)).collect();
+        let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+            "USDC".into(),
+            addresses,
+            1000,
+        ))]);
+        for i in 0..10 {
+            let sender = format!("addr{}", i);
+            blockchain.credit_native(&sender, 1);
+            blockchain.process_transaction(
+                Transaction::new(&sender, 10, "USDC", Method::Transfer)
+                    .with_seq(1)
+                    .with_destination(&format!("addr{}", 19 - i)),
+            )?;
+        }
+        Ok(blockchain)
+    };
+
+    // two independently built chains have differently seeded HashMaps but dump identically
+    let first = build()?;
+    let second = build()?;
+    assert!(first.dump_state() == second.dump_state());
+    assert!(first.dump_state() == first.dump_state());
+
+    let snapshot = first.contracts[0].balances_snapshot();
+    assert!(snapshot.windows(2).all(|w| w[0].0 < w[1].0));
+
+    Ok(())
+}
+
+fn test_method_names() -> Result<(), Error> {
+    for method in [
+        Method::BalanceOf,
+        Method::Transfer,
+        Method::TransferAll,
+        Method::ConditionalTransfer,
+        Method::TransferPartial,
+        Method::Noop,
+        Method::Deploy,
+    ] {
+        assert!(method.as_str().parse::<Method>()? == method);
+    }
+    assert!(Method::from_str("mint") == Err(Error::UnknownMethod));
+    assert!(Method::from_str("Transfer") == Err(Error::UnknownMethod));
+
+    Ok(())
+}
+
+fn test_balance_history() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into(), "addr2".into()],
+        1000,
+    ))]);
+
+    // block 1: addr1 -> addr2
+    blockchain.process_transaction(
+        Transaction::new("addr1", 100, "USDC", Method::Transfer)
+            .with_seq(1)
+            .with_destination("addr2"),
+    )?;
+    // block 2: a read, which changes nothing
+    blockchain
+        .process_transaction(Transaction::new("addr1", 0, "USDC", Method::BalanceOf).with_seq(2))?;
+    // block 3: addr2 -> addr3
+    blockchain.process_transaction(
+        Transaction::new("addr2", 50, "USDC", Method::Transfer)
+            .with_seq(1)
+            .with_destination("addr3"),
+    )?;
+    assert!(blockchain.block_height == 3);
+
+    assert!(blockchain.balance_history("USDC", "addr1") == vec![(1, 900)]);
+    assert!(blockchain.balance_history("USDC", "addr2") == vec![(1, 1100), (3, 1050)]);
+    assert!(blockchain.balance_history("USDC", "addr3") == vec![(3, 50)]);
+    assert!(blockchain.balance_history("WBTC", "addr1").is_empty());
+
+    Ok(())
+}
+
+fn test_default_sender() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into(), "addr2".into()],
+        1000,
+    ))]);
+    blockchain.set_default_sender("addr1");
+
+    // sequences 1, 2 and 3 are assigned by the chain
+    for _ in 0..3 {
+        blockchain.process_transaction(
+            Transaction::from_default(100, "USDC", Method::Transfer).with_destination("addr2"),
+        )?;
+    }
+    let addr1_bal =
+        blockchain.process_transaction(Transaction::from_default(0, "USDC", Method::BalanceOf))?;
+    assert!(addr1_bal == 700);
+
+    // an explicit sequence still overrides, so replaying one is rejected
+    let r = blockchain
+        .process_transaction(Transaction::from_default(0, "USDC", Method::BalanceOf).with_seq(4));
+    assert!(r == Err(Error::BadTransactionSequence));
+
+    // and an explicit sender ignores the default
+    let addr2_bal = blockchain
+        .process_transaction(Transaction::new("addr2", 0, "USDC", Method::BalanceOf).with_seq(1))?;
+    assert!(addr2_bal == 1300);
+
+    // without a default sender there's nobody to send it from
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["".into()],
+        1000,
+    ))]);
+    let orphan = Transaction::from_default(100, "USDC", Method::Transfer).with_destination("addr2");
+    assert!(blockchain.check_transaction(&orphan) == Err(Error::MissingSender));
+    assert!(blockchain.process_transaction(orphan) == Err(Error::MissingSender));
+    assert!(blockchain.block_height == 0 && blockchain.metrics().processed == 1);
+
+    Ok(())
+}
+
+// a token whose transfers always fail, used to check that upgrades change behavior
+#[derive(Clone)]
+struct FaultyToken {
+    inner: BasicToken,
+}
+
+impl TokenContract for FaultyToken {
+    fn contract(&self) -> String {
+        self.inner.contract()
+    }
+    fn balance_of(&self, address: String) -> u64 {
+        self.inner.balance_of(address)
+    }
+    fn transfer(&mut self, _sender: String, _amount: u64, _to: String) -> Result<(), Error> {
+        Err(Error::NotEnoughBalance)
+    }
+    fn iter_balances(&self) -> Box<dyn Iterator<Item = (String, u64)> + '_> {
+        self.inner.iter_balances()
+    }
+    fn load_ledger(&mut self, entries: Vec<(String, u64)>) {
+        self.inner.load_ledger(entries)
+    }
+    fn box_clone(&self) -> Box<dyn TokenContract> {
+        Box::new(self.clone())
+    }
+}
+
+fn test_upgrade_contract() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into(), "addr2".into()],
+        1000,
+    ))]);
+    blockchain.process_transaction(
+        Transaction::new("addr1", 100, "USDC", Method::Transfer)
+            .with_seq(1)
+            .with_destination("addr2"),
+    )?;
+
+    let faulty = || FaultyToken {
+        inner: BasicToken::new("USDC".into(), vec![], 0),
+    };
+
+    // the new implementation must live at the same address
+    let r = blockchain.upgrade_contract("WBTC", Box::new(faulty()));
+    assert!(r == Err(Error::ContractAddressMismatch));
+    blockchain.upgrade_contract("USDC", Box::new(faulty()))?;
+
+    // balances survived the upgrade
+    let addr1_bal = blockchain
+        .process_transaction(Transaction::new("addr1", 0, "USDC", Method::BalanceOf).with_seq(2))?;
+    let addr2_bal = blockchain
+        .process_transaction(Transaction::new("addr2", 0, "USDC", Method::BalanceOf).with_seq(1))?;
+    assert!(addr1_bal == 900);
+    assert!(addr2_bal == 1100);
+
+    // but transfers now go through the faulty implementation
+    let r = blockchain.process_transaction(
+        Transaction::new("addr1", 100, "USDC", Method::Transfer)
+            .with_seq(3)
+            .with_destination("addr2"),
+    );
+    assert!(r == Err(Error::NotEnoughBalance));
+
+    Ok(())
+}
+
+fn test_valid_until() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into(), "addr2".into()],
+        1000,
+    ))]);
+    // produce two blocks
+    for seq in 1..=2 {
+        blockchain.process_transaction(
+            Transaction::new("addr2", 0, "USDC", Method::BalanceOf).with_seq(seq),
+        )?;
+    }
+    assert!(blockchain.block_height == 2);
+
+    let r = blockchain.process_transaction(
+        Transaction::new("addr1", 0, "USDC", Method::BalanceOf)
+            .with_seq(1)
+            .with_valid_until(1),
+    );
+    assert!(r == Err(Error::TransactionExpired));
+
+    // the expired transaction didn't use up sequence 1, and valid_until == block_height is
+    // still in time
+    blockchain.process_transaction(
+        Transaction::new("addr1", 0, "USDC", Method::BalanceOf)
+            .with_seq(1)
+            .with_valid_until(2),
+    )?;
+
+    Ok(())
+}
+
+fn test_airdrop_weighted() -> Result<(), Error> {
+    let mut token = BasicToken::new("USDC".into(), vec!["addr1".into()], 5);
+
+    // 100 split 1:1:1 is 33 each, and the remainder goes to the largest (first) weight
+    token.airdrop_weighted(
+        100,
+        vec![
+            ("addr1".into(), 1),
+            ("addr2".into(), 1),
+            ("addr3".into(), 1),
+        ],
+    )?;
+    assert!(token.balance_of("addr1".into()) == 5 + 34);
+    assert!(token.balance_of("addr2".into()) == 33);
+    assert!(token.balance_of("addr3".into()) == 33);
+
+    // 1000 split 1:2:4 is 142/285/571, with 2 left over for the weight of 4
+    let mut token = BasicToken::new("USDC".into(), vec![], 0);
+    token.airdrop_weighted(
+        1000,
+        vec![
+            ("addr1".into(), 1),
+            ("addr2".into(), 2),
+            ("addr3".into(), 4),
+        ],
+    )?;
+    let total: u64 = token.balances_snapshot().iter().map(|(_, b)| b).sum();
+    assert!(total == 1000);
+    assert!(token.balance_of("addr3".into()) == 573);
+
+    let r = token.airdrop_weighted(1000, vec![("addr1".into(), 0)]);
+    assert!(r == Err(Error::ZeroTotalWeight));
+    // an overflow on any credit aborts the whole airdrop
+    token.airdrop_weighted(u64::MAX - 1000, vec![("addr1".into(), 1)])?;
+    let r = token.airdrop_weighted(2000, vec![("addr4".into(), 1), ("addr1".into(), 1)]);
+    assert!(r == Err(Error::BalanceOverflow));
+    assert!(token.balance_of("addr4".into()) == 0);
+
+    Ok(())
+}
+
+fn test_metrics() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into(), "addr2".into()],
+        1000,
+    ))]);
+    let transfer = |seq, amount| {
+        Transaction::new("addr1", amount, "USDC", Method::Transfer)
+            .with_seq(seq)
+            .with_destination("addr2")
+    };
+
+    blockchain.process_transaction(transfer(1, 100))?;
+    blockchain.process_transaction(transfer(2, 200))?;
+    let _ = blockchain.process_transaction(transfer(2, 200));
+    let _ = blockchain.process_transaction(transfer(3, 5000));
+    let _ = blockchain
+        .process_transaction(Transaction::new("addr2", 0, "WBTC", Method::BalanceOf).with_seq(1));
+    let _ = blockchain
+        .process_transaction(Transaction::new("addr2", 0, "WBTC", Method::BalanceOf).with_seq(1));
+
+    let metrics = blockchain.metrics();
+    assert!(metrics.processed == 6);
+    assert!(metrics.succeeded == 2);
+    assert!(metrics.volume == 300);
+    assert!(metrics.failures_of(&Error::BadTransactionSequence) == 1);
+    assert!(metrics.failures_of(&Error::NotEnoughBalance) == 1);
+    // a missing contract is caught before the sequence is used up
+    assert!(metrics.failures_of(&Error::ContractNotFound) == 2);
+    // errors are counted by kind, whatever they carry
+    let mut metrics = Metrics::default();
+    metrics.record(&Err(Error::ParseError { line: 1 }));
+    metrics.record(&Err(Error::ParseError { line: 2 }));
+    assert!(metrics.failures_of(&Error::ParseError { line: 3 }) == 2);
+
+    // only what actually moves counts: a self-transfer that does nothing, and the part of
+    // a transfer its token holds back, don't
+    let mut blockchain = Blockchain::new(vec![Box::new(
+        BasicToken::new("USDC".into(), vec!["addr1".into()], 1000)
+            .with_transfer_hook(Box::new(FeeHook::new(1000))),
+    )])
+    .with_self_transfer_policy(SelfTransferPolicy::NoOp);
+    blockchain.process_transaction(transfer(1, 100))?;
+    blockchain.process_transaction(transfer(2, 100).with_destination("addr1"))?;
+    assert!(blockchain.metrics().succeeded == 2);
+    assert!(blockchain.metrics().volume == 90);
+
+    Ok(())
+}
+
+fn test_transfer_all() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into(), "addr2".into()],
+        1000,
+    ))]);
+    blockchain.credit_native("addr2", 1_000_000);
+
+    let moved = blockchain.process_transaction(
+        Transaction::new("addr1", 0, "USDC", Method::TransferAll)
+            .with_seq(1)
+            .with_destination("addr3"),
+    )?;
+    assert!(moved == 1000);
+
+    // paying gas comes out of the native balance, so the whole token balance still moves
+    let moved = blockchain.process(
+        Transaction::new("addr2", 0, "USDC", Method::TransferAll)
+            .with_seq(1)
+            .with_destination("addr3")
+            .with_gas(50_000, 1),
+    )?;
+    assert!(moved == 1000);
+    assert!(blockchain.native_balance_of("addr2") == 1_000_000 - (GAS_BASE + GAS_TRANSFER));
+
+    for (address, seq, expected) in [("addr1", 2, 0), ("addr2", 2, 0), ("addr3", 1, 2000)] {
+        let balance = blockchain.process_transaction(
+            Transaction::new(address, 0, "USDC", Method::BalanceOf).with_seq(seq),
+        )?;
+        assert!(balance == expected);
+    }
+    assert!(blockchain.metrics().volume == 2000);
+
+    Ok(())
+}
+
+fn test_check_transaction() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into(), "addr2".into()],
+        1000,
+    ))]);
+    let transfer = Transaction::new("addr1", 100, "USDC", Method::Transfer)
+        .with_seq(1)
+        .with_destination("addr2");
+
+    // checking twice is fine, nothing gets consumed
+    blockchain.check_transaction(&transfer)?;
+    blockchain.check_transaction(&transfer)?;
+    let before = blockchain.dump_state();
+
+    let wrong_contract = Transaction::new("addr1", 0, "WBTC", Method::BalanceOf).with_seq(1);
+    assert!(blockchain.check_transaction(&wrong_contract) == Err(Error::ContractNotFound));
+    let expired = Transaction::new("addr1", 0, "USDC", Method::BalanceOf)
+        .with_seq(1)
+        .with_valid_until(0);
+    blockchain.check_transaction(&expired)?;
+    assert!(blockchain.dump_state() == before);
+
+    blockchain.process_transaction(transfer.clone())?;
+    assert!(blockchain.check_transaction(&transfer) == Err(Error::BadTransactionSequence));
+    assert!(blockchain.check_transaction(&expired) == Err(Error::TransactionExpired));
+
+    Ok(())
+}
+
+// stand-in for a non-fungible contract, only its kind matters to the tests
+#[derive(Clone)]
+struct NftContract {
+    inner: BasicToken,
+}
+
+impl TokenContract for NftContract {
+    fn contract(&self) -> String {
+        self.inner.contract()
+    }
+    fn balance_of(&self, address: String) -> u64 {
+        self.inner.balance_of(address)
+    }
+    fn transfer(&mut self, sender: String, amount: u64, to: String) -> Result<(), Error> {
+        self.inner.transfer(sender, amount, to)
+    }
+    fn iter_balances(&self) -> Box<dyn Iterator<Item = (String, u64)> + '_> {
+        self.inner.iter_balances()
+    }
+    fn load_ledger(&mut self, entries: Vec<(String, u64)>) {
+        self.inner.load_ledger(entries)
+    }
+    fn kind(&self) -> ContractKind {
+        ContractKind::NonFungible
+    }
+    fn box_clone(&self) -> Box<dyn TokenContract> {
+        Box::new(self.clone())
+    }
+}
+
+fn test_contract_kind() -> Result<(), Error> {
+    let blockchain = Blockchain::new(vec![
+        Box::new(BasicToken::new("USDC".into(), vec![], 0)),
+        Box::new(NftContract {
+            inner: BasicToken::new("PUNK".into(), vec![], 0),
+        }),
+    ]);
+    assert!(blockchain.contract_kind("USDC") == Some(ContractKind::Fungible));
+    assert!(blockchain.contract_kind("PUNK") == Some(ContractKind::NonFungible));
+    assert!(blockchain.contract_kind("WBTC").is_none());
+
+    Ok(())
+}
+
+fn test_balances_of() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into(), "addr2".into(), "addr3".into()],
+        1000,
+    ))]);
+    blockchain.process_transaction(
+        Transaction::new("addr1", 250, "USDC", Method::Transfer)
+            .with_seq(1)
+            .with_destination("addr4"),
+    )?;
+
+    let addresses: Vec<String> = ["addr4", "addr1", "addr2", "addr9", "addr3"]
+        .iter()
+        .map(|a| a.to_string())
+        .collect();
+    let balances = blockchain.balances_of("USDC", &addresses)?;
+    assert!(balances == vec![250, 750, 1000, 0, 1000]);
+    assert!(blockchain.balances_of("WBTC", &addresses) == Err(Error::ContractNotFound));
+
+    Ok(())
+}
+
+fn test_vault() -> Result<(), Error> {
+    let mut vault = VaultContract::new("vUSDC".into(), "USDC".into(), 100);
+    assert!(vault.underlying() == "USDC");
+
+    assert!(vault.deposit("addr1".into(), 1000)? == 1000);
+    assert!(vault.deposit("addr2".into(), 1000)? == 1000);
+
+    // 10 blocks at 1% a block: 2000 grows to 2200
+    vault.accrue(10);
+    assert!(vault.total_assets() == 2200);
+    // however long it accrues, the assets saturate rather than overflow
+    let mut grown = vault.clone();
+    grown.accrue(u64::MAX);
+    assert!(grown.total_assets() == u64::MAX);
+
+    // a later depositor pays the higher share price
+    assert!(vault.deposit("addr3".into(), 1100)? == 1000);
+
+    // withdrawing everything pays out deposits plus yield, and never more
+    let mut withdrawn = 0;
+    for address in ["addr1", "addr2", "addr3"] {
+        let shares = vault.balance_of(address.into());
+        withdrawn += vault.withdraw(address.into(), shares)?;
+    }
+    assert!(withdrawn == 1000 + 1000 + 1100 + 200);
+    assert!(vault.total_assets() == 0);
+    assert!(vault.withdraw("addr1".into(), 1) == Err(Error::NotEnoughBalance));
+
+    // shares round down, so a single share is never worth more than its part of the assets
+    let mut vault = VaultContract::new("vUSDC".into(), "USDC".into(), 333);
+    vault.deposit("addr1".into(), 3)?;
+    vault.accrue(1);
+    vault.deposit("addr2".into(), 7)?;
+    let total = vault.total_assets();
+    let owed = vault.preview_withdraw(vault.balance_of("addr1".into()))
+        + vault.preview_withdraw(vault.balance_of("addr2".into()));
+    assert!(owed <= total);
+
+    // depositing only mints shares, the underlying has to be moved separately
+    let mut usdc = BasicToken::new("USDC".into(), vec!["addr1".into()], 1000);
+    let mut vault = VaultContract::new("vUSDC".into(), "USDC".into(), 0);
+    vault.deposit("addr1".into(), 400)?;
+    assert!(usdc.balance_of("addr1".into()) == 1000 && usdc.balance_of("vUSDC".into()) == 0);
+    usdc.transfer("addr1".into(), 400, vault.contract())?;
+    assert!(usdc.balance_of("vUSDC".into()) == vault.total_assets());
+
+    // shares that would overflow aren't minted or moved
+    let mut vault = VaultContract::new("vUSDC".into(), "USDC".into(), 0);
+    vault.load_ledger(vec![("addr1".into(), u64::MAX), ("addr2".into(), 1)]);
+    assert!(vault.deposit("addr3".into(), 1) == Err(Error::BalanceOverflow));
+    assert!(vault.balance_of("addr3".into()) == 0 && vault.total_assets() == 0);
+    let r = vault.transfer("addr2".into(), 1, "addr1".into());
+    assert!(r == Err(Error::BalanceOverflow));
+    assert!(vault.balance_of("addr1".into()) == u64::MAX && vault.balance_of("addr2".into()) == 1);
+
+    Ok(())
+}
+
+fn test_memo() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into(), "addr2".into()],
+        1000,
+    ))]);
+    let transfer = Transaction::new("addr1", 100, "USDC", Method::Transfer)
+        .with_seq(1)
+        .with_destination("addr2");
+
+    // the memo doesn't change which transaction this is
+    let with_memo = transfer.clone().with_memo("invoice 42");
+    assert!(with_memo.hash() == transfer.hash());
+    blockchain.process_transaction(with_memo)?;
+
+    assert!(
+        blockchain.events()
+            == [Event::Transfer {
+                contract: "USDC".into(),
+                from: "addr1".into(),
+                to: "addr2".into(),
+                amount: 100,
+                memo: Some("invoice 42".into()),
+            }]
+    );
+    let receipt = blockchain.receipt(transfer.hash()).unwrap();
+    assert!(receipt.memo == Some("invoice 42".into()));
+    assert!(receipt.block_height == 1);
+
+    // a long memo is fine up to the cap, and rejected past it without using the sequence
+    let long = "x".repeat(MAX_MEMO_LEN + 1);
+    let r = blockchain.process_transaction(
+        Transaction::new("addr1", 0, "USDC", Method::BalanceOf)
+            .with_seq(2)
+            .with_memo(&long),
+    );
+    assert!(r == Err(Error::MemoTooLong));
+    blockchain.process_transaction(
+        Transaction::new("addr1", 0, "USDC", Method::BalanceOf)
+            .with_seq(2)
+            .with_memo(&long[1..]),
+    )?;
+
+    Ok(())
+}
+
+fn test_top_holders() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into(), "addr2".into(), "addr3".into()],
+        1000,
+    ))]);
+    for (sender, amount, destination) in [
+        ("addr1", 300, "addr2"),
+        ("addr3", 1000, "addr4"),
+        ("addr2", 50, "addr5"),
+    ] {
+        blockchain.process_transaction(
+            Transaction::new(sender, amount, "USDC", Method::Transfer)
+                .with_seq(1)
+                .with_destination(destination),
+        )?;
+    }
+
+    // addr2 1250, addr4 1000, addr1 700, addr5 50, and addr3 is emptied
+    let top = blockchain.top_holders("USDC", 3)?;
+    assert!(
+        top == vec![
+            ("addr2".into(), 1250),
+            ("addr4".into(), 1000),
+            ("addr1".into(), 700)
+        ]
+    );
+    assert!(blockchain.top_holders("USDC", 10)?.len() == 4);
+    assert!(blockchain.top_holders("WBTC", 3) == Err(Error::ContractNotFound));
+
+    Ok(())
+}
+
+fn test_genesis_nonce() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into()],
+        1000,
+    ))])
+    .with_genesis_nonce(100);
+
+    for seq in [1, 100] {
+        let r = blockchain.process_transaction(
+            Transaction::new("addr1", 0, "USDC", Method::BalanceOf).with_seq(seq),
+        );
+        assert!(r == Err(Error::BadTransactionSequence));
+    }
+    blockchain.process_transaction(
+        Transaction::new("addr1", 0, "USDC", Method::BalanceOf).with_seq(101),
+    )?;
+
+    // chain-assigned sequences start from the genesis nonce too
+    blockchain.set_default_sender("addr2");
+    blockchain.process_transaction(Transaction::from_default(0, "USDC", Method::BalanceOf))?;
+    assert!(blockchain.receipts()[1].sequence == 101);
+
+    Ok(())
+}
+
+fn test_conditional_transfer() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into(), "addr2".into()],
+        1000,
+    ))]);
+    let conditional = |seq, min_balance| {
+        Transaction::new("addr1", 400, "USDC", Method::ConditionalTransfer)
+            .with_seq(seq)
+            .with_destination("addr2")
+            .with_condition(min_balance)
+    };
+
+    // 1000 >= 1000, so the transfer goes ahead
+    assert!(blockchain.process_transaction(conditional(1, 1000))? == 400);
+    assert!(!blockchain.receipts()[0].skipped);
+
+    // 600 < 1000, so it's skipped without an error
+    let before = blockchain.balances_of("USDC", &["addr1".into(), "addr2".into()])?;
+    assert!(blockchain.process_transaction(conditional(2, 1000))? == 0);
+    assert!(blockchain.receipts()[1].skipped);
+    assert!(blockchain.balances_of("USDC", &["addr1".into(), "addr2".into()])? == before);
+    assert!(before == vec![600, 1400]);
+    assert!(blockchain.events().len() == 1);
+
+    Ok(())
+}
+
+fn test_account_lifecycle() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into()],
+        1000,
+    ))]);
+
+    blockchain.create_account("addr2")?;
+    assert!(blockchain.create_account("addr2") == Err(Error::AccountExists));
+    assert!(blockchain.delete_account("addr3") == Err(Error::AccountNotFound));
+
+    // addr1 holds USDC and can't be deleted
+    blockchain
+        .process_transaction(Transaction::new("addr1", 0, "USDC", Method::BalanceOf).with_seq(1))?;
+    assert!(blockchain.delete_account("addr1") == Err(Error::AccountNotEmpty));
+
+    // addr2 is empty, so it can go
+    let replay = Transaction::new("addr2", 0, "USDC", Method::BalanceOf).with_seq(1);
+    blockchain.process_transaction(replay.clone())?;
+    blockchain.delete_account("addr2")?;
+
+    // recreating it doesn't reopen its old sequences
+    blockchain.create_account("addr2")?;
+    assert!(blockchain.process_transaction(replay) == Err(Error::BadTransactionSequence));
+    blockchain
+        .process_transaction(Transaction::new("addr2", 0, "USDC", Method::BalanceOf).with_seq(2))?;
+
+    Ok(())
+}
+
+// takes 2% of every transfer for a treasury, and refuses to send to a blocked address
+#[derive(Clone)]
+struct TaxHook {
+    treasury: String,
+    blocked: String,
+}
+
+impl TransferHook for TaxHook {
+    fn on_transfer(&mut self, _from: &str, to: &str, amount: u64) -> Result<u64, Error> {
+        if to == self.blocked {
+            return Err(Error::NotEnoughBalance);
+        }
+        let (_, net) = math::split_bps(amount, 200);
+        Ok(net)
+    }
+    fn withheld_to(&self) -> Option<String> {
+        Some(self.treasury.clone())
+    }
+    fn box_clone(&self) -> Box<dyn TransferHook> {
+        Box::new(self.clone())
+    }
+}
+
+fn test_transfer_hook() -> Result<(), Error> {
+    let mut token = BasicToken::new("USDC".into(), vec!["addr1".into()], 1000).with_transfer_hook(
+        Box::new(TaxHook {
+            treasury: "treasury".into(),
+            blocked: "addr9".into(),
+        }),
+    );
+
+    token.transfer("addr1".into(), 500, "addr2".into())?;
+    assert!(token.balance_of("addr1".into()) == 500);
+    assert!(token.balance_of("addr2".into()) == 490);
+    assert!(token.balance_of("treasury".into()) == 10);
+
+    // a hook error leaves every balance as it was
+    let before = token.balances_snapshot();
+    assert!(token.transfer("addr1".into(), 100, "addr9".into()) == Err(Error::NotEnoughBalance));
+    assert!(token.balances_snapshot() == before);
+
+    Ok(())
+}
+
+fn test_is_replay() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into()],
+        1000,
+    ))]);
+    let read = |seq| Transaction::new("addr1", 0, "USDC", Method::BalanceOf).with_seq(seq);
+    blockchain.process_transaction(read(1))?;
+    blockchain.process_transaction(read(2))?;
+
+    assert!(blockchain.is_replay(&read(1)));
+    assert!(blockchain.is_replay(&read(2)));
+    assert!(!blockchain.is_replay(&read(3)));
+    assert!(!blockchain.is_replay(&read(10)));
+
+    Ok(())
+}
+
+fn test_block_reward() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into()],
+        1000,
+    ))])
+    .with_block_reward("miner", 50);
+
+    assert!(blockchain.produce_block() == 1);
+    assert!(blockchain.produce_block() == 2);
+    assert!(blockchain.native_balance_of("miner") == 100);
+
+    // blocks sealed by a transaction pay out too
+    blockchain
+        .process_transaction(Transaction::new("addr1", 0, "USDC", Method::BalanceOf).with_seq(1))?;
+    assert!(blockchain.native_balance_of("miner") == 150);
+    let block = &blockchain.blocks()[2];
+    assert!(block.height == 3 && block.transactions.len() == 1);
+    assert!(block.coinbase == Some(("miner".into(), 50)));
+
+    // without a reward nothing is minted
+    let mut blockchain = Blockchain::new(vec![]).with_block_reward("miner", 0);
+    blockchain.produce_block();
+    assert!(blockchain.native_balance_of("miner") == 0);
+    assert!(blockchain.blocks()[0].coinbase.is_none());
+
+    Ok(())
+}
+
+fn test_contract_addresses() -> Result<(), Error> {
+    let build = || {
+        Blockchain::new(vec![
+            Box::new(BasicToken::new("USDC".into(), vec!["addr1".into()], 1000)),
+            Box::new(BasicToken::new("WBTC".into(), vec![], 0)),
+        ])
+    };
+    let mut blockchain = build();
+
+    let r = blockchain
+        .process_transaction(Transaction::new("WBTC", 0, "USDC", Method::BalanceOf).with_seq(1));
+    assert!(r == Err(Error::InvalidSender));
+    let to_contract = Transaction::new("addr1", 100, "USDC", Method::Transfer)
+        .with_seq(1)
+        .with_destination("WBTC");
+    let r = blockchain.process_transaction(to_contract.clone());
+    assert!(r == Err(Error::InvalidDestination));
+
+    // some designs let contracts hold balances
+    let mut blockchain = build().with_contract_destinations(true);
+    blockchain.process_transaction(to_contract)?;
+    let r = blockchain
+        .process_transaction(Transaction::new("WBTC", 0, "USDC", Method::BalanceOf).with_seq(1));
+    assert!(r == Err(Error::InvalidSender));
+
+    Ok(())
+}
+
+fn test_iter_balances() -> Result<(), Error> {
+    let mut token = BasicToken::new("USDC".into(), vec!["addr1".into(), "addr2".into()], 1000);
+    token.transfer("addr1".into(), 300, "addr3".into())?;
+
+    let mut balances: Vec<(String, u64)> = token.iter_balances().collect();
+    balances.sort();
+    assert!(
+        balances
+            == vec![
+                ("addr1".into(), 700),
+                ("addr2".into(), 1000),
+                ("addr3".into(), 300)
+            ]
+    );
+    assert!(token.balances_snapshot() == balances);
+
+    Ok(())
+}
+
+fn test_prune_zero_balances() -> Result<(), Error> {
+    let mut token = BasicToken::new("USDC".into(), vec!["addr1".into(), "addr2".into()], 1000);
+    token.transfer("addr1".into(), 1000, "addr2".into())?;
+    assert!(token.iter_balances().count() == 2);
+
+    assert!(token.prune_zero_balances() == 1);
+    assert!(token.balances_snapshot() == vec![("addr2".into(), 2000)]);
+    assert!(token.balance_of("addr1".into()) == 0);
+    assert!(token.prune_zero_balances() == 0);
+
+    let mut token =
+        BasicToken::new("USDC".into(), vec!["addr1".into()], 1000).with_auto_prune(true);
+    token.transfer("addr1".into(), 1000, "addr2".into())?;
+    assert!(token.balances_snapshot() == vec![("addr2".into(), 1000)]);
+
+    Ok(())
+}
+
+fn test_receipt_builder() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into(), "addr2".into()],
+        1000,
+    ))]);
+    let transfer = Transaction::new("addr1", 100, "USDC", Method::Transfer)
+        .with_seq(1)
+        .with_destination("addr2")
+        .with_memo("rent");
+    blockchain.process_transaction(transfer.clone())?;
+    let produced = &blockchain.receipts()[0];
+
+    let expected = ReceiptBuilder::new("addr1", Method::Transfer)
+        .sequence(1)
+        .block_height(1)
+        .contract("USDC")
+        .memo("rent")
+        .build();
+    assert!(produced.matches(&expected));
+    assert!(expected.matches(produced));
+    assert!(*produced != expected);
+    let exact = ReceiptBuilder::new("addr1", Method::Transfer)
+        .hash(transfer.hash())
+        .sequence(1)
+        .block_height(1)
+        .contract("USDC")
+        .value(0)
+        .memo("rent")
+        .build();
+    assert!(*produced == exact);
+
+    let skipped = ReceiptBuilder::new("addr1", Method::Transfer)
+        .sequence(1)
+        .block_height(1)
+        .contract("USDC")
+        .memo("rent")
+        .skipped(true)
+        .build();
+    assert!(!produced.matches(&skipped));
+
+    Ok(())
+}
+
+fn test_blacklist() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into(), "addr2".into()],
+        1000,
+    ))]);
+    blockchain.blacklist("addr1");
+
+    let read = Transaction::new("addr1", 0, "USDC", Method::BalanceOf).with_seq(1);
+    let send = Transaction::new("addr1", 100, "USDC", Method::Transfer)
+        .with_seq(1)
+        .with_destination("addr2");
+    let receive = Transaction::new("addr2", 100, "USDC", Method::Transfer)
+        .with_seq(1)
+        .with_destination("addr1");
+    for transaction in [read.clone(), send.clone(), receive.clone()] {
+        assert!(blockchain.process_transaction(transaction) == Err(Error::Blacklisted));
+    }
+
+    // nothing was consumed, so the same transactions go through once unblacklisted
+    blockchain.unblacklist("addr1");
+    blockchain.process_transaction(send)?;
+    blockchain.process_transaction(receive)?;
+
+    Ok(())
+}
+
+fn test_verify_deterministic() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![
+        Box::new(BasicToken::new(
+            "USDC".into(),
+            vec!["addr1".into(), "addr2".into()],
+            1000,
+        )),
+        Box::new(BasicToken::new("WBTC".into(), vec!["addr3".into()], 10)),
+    ]);
+    assert!(blockchain.verify_deterministic());
+
+    blockchain.process_transaction(
+        Transaction::new("addr1", 100, "USDC", Method::Transfer)
+            .with_seq(1)
+            .with_destination("addr2"),
+    )?;
+    blockchain.produce_block();
+    blockchain.process_transaction(
+        Transaction::new("addr3", 0, "WBTC", Method::TransferAll)
+            .with_seq(1)
+            .with_destination("addr1"),
+    )?;
+    // failed transactions aren't part of any block
+    let _ = blockchain.process_transaction(
+        Transaction::new("addr2", 5000, "USDC", Method::Transfer)
+            .with_seq(1)
+            .with_destination("addr1"),
+    );
+    assert!(blockchain.block_height == 3);
+    assert!(blockchain.verify_deterministic());
+
+    // a balance written behind the chain's back doesn't survive a replay
+    let mut ledger = blockchain.contracts[0].balances_snapshot();
+    ledger[0].1 += 1;
+    blockchain.contracts[0].load_ledger(ledger);
+    assert!(!blockchain.verify_deterministic());
+
+    Ok(())
+}
+
+fn test_math() -> Result<(), Error> {
+    assert!(math::mul_bps(10_000, 250) == 250);
+    assert!(math::mul_bps(999, 1) == 0);
+    assert!(math::mul_bps(u64::MAX, 10_000) == u64::MAX);
+    assert!(math::mul_bps(u64::MAX, 5_000) == u64::MAX / 2);
+    // more than 100% saturates instead of overflowing
+    assert!(math::mul_bps(u64::MAX, u16::MAX) == u64::MAX);
+
+    for (value, bps) in [
+        (u64::MAX, 1),
+        (u64::MAX, 9_999),
+        (12_345, 333),
+        (1, 5_000),
+        (7, 20_000),
+    ] {
+        let (part, rest) = math::split_bps(value, bps);
+        assert!(part + rest == value);
+    }
+    assert!(math::split_bps(7, 20_000) == (7, 0));
+
+    Ok(())
+}
+
+fn test_max_accounts() -> Result<(), Error> {
+    let mut token = BasicToken::new("USDC".into(), vec!["addr1".into(), "addr2".into()], 1000)
+        .with_max_accounts(3);
+
+    token.transfer("addr1".into(), 1000, "addr3".into())?;
+    let r = token.transfer("addr2".into(), 100, "addr4".into());
+    assert!(r == Err(Error::AccountLimitReached));
+    token.transfer("addr2".into(), 100, "addr3".into())?;
+
+    // addr1 was emptied, pruning it frees a slot
+    assert!(token.prune_zero_balances() == 1);
+    token.transfer("addr2".into(), 100, "addr4".into())?;
+    assert!(token.balance_of("addr4".into()) == 100);
+
+    Ok(())
+}
+
+fn test_signing() -> Result<(), Error> {
+    let keypair = MockKeypair::new(42);
+    assert!(keypair.address() == MockKeypair::new(42).address());
+    assert!(keypair.address() != MockKeypair::new(43).address());
+
+    let sender = keypair.address();
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec![sender.clone()],
+        1000,
+    ))]);
+    let transfer = Transaction::new(&sender, 100, "USDC", Method::Transfer)
+        .with_seq(1)
+        .with_destination("addr2");
+    let signature = keypair.sign(&transfer);
+
+    // bumping the amount after signing breaks the signature
+    let mut tampered = transfer.clone().with_signature(signature.clone());
+    tampered.amount = 1000;
+    assert!(blockchain.process_transaction(tampered) == Err(Error::InvalidSignature));
+
+    // as does someone else's key
+    let forged = transfer
+        .clone()
+        .with_signature(MockKeypair::new(7).sign(&transfer));
+    assert!(blockchain.process_transaction(forged) == Err(Error::InvalidSignature));
+
+    blockchain.process_transaction(transfer.with_signature(signature))?;
+    assert!(blockchain.balances_of("USDC", &["addr2".into()])? == vec![100]);
+
+    Ok(())
+}
+
+fn test_base_fee() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into(), "addr2".into()],
+        1000,
+    ))])
+    .with_base_fee(1000, 24_000);
+    blockchain.credit_native("addr1", u64::MAX / 2);
+    let transfer = |seq, max_fee| {
+        Transaction::new("addr1", 1, "USDC", Method::Transfer)
+            .with_seq(seq)
+            .with_destination("addr2")
+            .with_gas(50_000, 1)
+            .with_max_fee(max_fee)
+    };
+
+    let r = blockchain.process(transfer(1, 999));
+    assert!(r == Err(Error::FeeTooLow));
+    // a transaction the chain won't take at all fails on that first
+    let r = blockchain.process(transfer(1, 999).with_valid_from(5));
+    assert!(r == Err(Error::TransactionNotYetValid));
+
+    // every transfer uses 26_000 gas, over the 24_000 target, so the fee keeps rising. each
+    // one burns the base fee on top of its gas.
+    let mut base_fee = blockchain.current_base_fee();
+    for seq in 1..=3 {
+        let before = blockchain.native_balance_of("addr1");
+        blockchain.process(transfer(seq, u64::MAX))?;
+        let paid = before - blockchain.native_balance_of("addr1");
+        assert!(paid == GAS_BASE + GAS_TRANSFER + base_fee);
+        assert!(blockchain.current_base_fee() > base_fee);
+        base_fee = blockchain.current_base_fee();
+    }
+    assert!(blockchain.blocks()[0].gas_used == GAS_BASE + GAS_TRANSFER);
+    assert!(blockchain.current_base_fee() == 1030);
+
+    // an unmetered transaction leaves it alone
+    blockchain
+        .process_transaction(Transaction::new("addr1", 0, "USDC", Method::BalanceOf).with_seq(4))?;
+    assert!(blockchain.current_base_fee() == base_fee);
+
+    // empty blocks bring it back down
+    for _ in 0..3 {
+        blockchain.produce_block();
+        assert!(blockchain.current_base_fee() < base_fee);
+        base_fee = blockchain.current_base_fee();
+    }
+
+    // and so do blocks using less than the target
+    for seq in 5..=7 {
+        blockchain.process(
+            Transaction::new("addr1", 0, "USDC", Method::BalanceOf)
+                .with_seq(seq)
+                .with_gas(50_000, 1)
+                .with_max_fee(u64::MAX),
+        )?;
+        assert!(blockchain.current_base_fee() < base_fee);
+        base_fee = blockchain.current_base_fee();
+    }
+
+    Ok(())
+}
+
+fn test_import_sequences() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into(), "addr2".into()],
+        1000,
+    ))]);
+    for (address, seq) in [("addr1", 1), ("addr1", 2), ("addr2", 5)] {
+        blockchain.process_transaction(
+            Transaction::new(address, 0, "USDC", Method::BalanceOf).with_seq(seq),
+        )?;
+    }
+
+    let mut sequences = blockchain.export_sequences();
+    assert!(sequences.len() == 2 && sequences["addr1"] == 2 && sequences["addr2"] == 5);
+
+    // moving sequences forward, or adding accounts, is fine
+    sequences.insert("addr1".into(), 10);
+    sequences.insert("addr3".into(), 3);
+    blockchain.import_sequences(sequences.clone())?;
+    assert!(blockchain.export_sequences() == sequences);
+    let r = blockchain
+        .process_transaction(Transaction::new("addr1", 0, "USDC", Method::BalanceOf).with_seq(10));
+    assert!(r == Err(Error::BadTransactionSequence));
+
+    // lowering one, or dropping one, is not
+    let mut lowered = sequences.clone();
+    lowered.insert("addr2".into(), 4);
+    assert!(blockchain.import_sequences(lowered) == Err(Error::SequenceRegression));
+    let mut dropped = sequences.clone();
+    dropped.remove("addr3");
+    assert!(blockchain.import_sequences(dropped) == Err(Error::SequenceRegression));
+    assert!(blockchain.export_sequences() == sequences);
+
+    Ok(())
+}
+
+fn test_subscribe() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![
+        Box::new(BasicToken::new("USDC".into(), vec!["addr1".into()], 1000)),
+        Box::new(BasicToken::new("WBTC".into(), vec!["addr1".into()], 1000)),
+    ]);
+
+    // both subscribers see USDC transfers, in the order they subscribed
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    let first = seen.clone();
+    let first = blockchain.subscribe(
+        "USDC",
+        Box::new(move |e: &Event| first.borrow_mut().push((1, e.clone()))),
+    );
+    let second = seen.clone();
+    blockchain.subscribe(
+        "USDC",
+        Box::new(move |e: &Event| second.borrow_mut().push((2, e.clone()))),
+    );
+
+    let transfer = |contract, seq| {
+        Transaction::new("addr1", 10, contract, Method::Transfer)
+            .with_seq(seq)
+            .with_destination("addr2")
+    };
+    blockchain.process_transaction(transfer("USDC", 1))?;
+    blockchain.process_transaction(transfer("WBTC", 2))?;
+    assert!(seen.borrow().len() == 2);
+    assert!(seen.borrow()[0] == (1, blockchain.events()[0].clone()));
+    assert!(seen.borrow()[1] == (2, blockchain.events()[0].clone()));
+
+    // once unsubscribed, only the second one hears about it
+    blockchain.unsubscribe(first);
+    blockchain.process_transaction(transfer("USDC", 3))?;
+    assert!(seen.borrow().len() == 3);
+    assert!(seen.borrow()[2] == (2, blockchain.events()[2].clone()));
+
+    Ok(())
+}
+
+fn test_ranking() -> Result<(), Error> {
+    let addresses = vec![
+        "addr4".into(),
+        "addr2".into(),
+        "addr3".into(),
+        "addr1".into(),
+    ];
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        addresses,
+        500,
+    ))]);
+    blockchain.process_transaction(
+        Transaction::new("addr3", 100, "USDC", Method::Transfer)
+            .with_seq(1)
+            .with_destination("addr5"),
+    )?;
+
+    // four holders tie at 500, and come out by address whatever order they were added in
+    let top = blockchain.top_holders("USDC", 5)?;
+    let order: Vec<&str> = top.iter().map(|(a, _)| a.as_str()).collect();
+    assert!(order == ["addr1", "addr2", "addr4", "addr3", "addr5"]);
+
+    // entries that tie on both keys keep their input order
+    let mut entries = vec![
+        ("b".to_string(), 1),
+        ("a".to_string(), 1),
+        ("b".to_string(), 1),
+        ("c".to_string(), 2),
+    ];
+    ranking::rank_by_balance(&mut entries);
+    assert!(entries[0].0 == "c" && entries[1].0 == "a" && entries[2].0 == "b");
+
+    Ok(())
+}
+
+fn test_transfer_partial() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into()],
+        1000,
+    ))]);
+    let partial = |seq, amount| {
+        Transaction::new("addr1", amount, "USDC", Method::TransferPartial)
+            .with_seq(seq)
+            .with_destination("addr2")
+    };
+
+    assert!(blockchain.process_transaction(partial(1, 600))? == 600);
+    // only 400 left to send
+    assert!(blockchain.process_transaction(partial(2, 600))? == 400);
+    assert!(blockchain.receipts()[1].value == 400);
+    // and then nothing, which still isn't an error
+    assert!(blockchain.process_transaction(partial(3, 600))? == 0);
+    assert!(blockchain.balances_of("USDC", &["addr1".into(), "addr2".into()])? == vec![0, 1000]);
+
+    Ok(())
+}
+
+fn test_from_csv() -> Result<(), Error> {
+    let token = BasicToken::from_csv("USDC".into(), "addr1,1000\n\naddr2, 250\n")?;
+    assert!(token.balance_of("addr1".into()) == 1000);
+    assert!(token.balance_of("addr2".into()) == 250);
+
+    // duplicates sum
+    let token = BasicToken::from_csv("USDC".into(), "addr1,1000\naddr2,5\naddr1,24")?;
+    assert!(token.balance_of("addr1".into()) == 1024);
+
+    // the blank line still counts towards the line number
+    let r = BasicToken::from_csv("USDC".into(), "addr1,1000\n\naddr2 300\n");
+    assert!(r.err() == Some(Error::ParseError { line: 3 }));
+    let r = BasicToken::from_csv("USDC".into(), "addr1,lots");
+    assert!(r.err() == Some(Error::ParseError { line: 1 }));
+    let r = BasicToken::from_csv("USDC".into(), "addr1,1\n,1");
+    assert!(r.err() == Some(Error::ParseError { line: 2 }));
+
+    Ok(())
+}
+
+fn test_rollback() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into()],
+        1000,
+    ))])
+    .with_confirmations_required(2)
+    .with_block_reward("miner", 5);
+
+    for seq in 1..=4 {
+        blockchain.process_transaction(
+            Transaction::new("addr1", 100, "USDC", Method::Transfer)
+                .with_seq(seq)
+                .with_destination("addr2"),
+        )?;
+    }
+    blockchain.produce_block();
+    assert!(blockchain.block_height == 5);
+
+    // block 3 has two blocks on top of it, block 4 only one
+    assert!(blockchain.is_final(3));
+    assert!(!blockchain.is_final(4));
+    assert!(!blockchain.is_final(6));
+
+    // rolling back to 2 would undo the final block 3
+    assert!(blockchain.rollback_to_height(2) == Err(Error::CannotRollbackFinalized));
+    assert!(blockchain.block_height == 5);
+
+    blockchain.rollback_to_height(3)?;
+    assert!(blockchain.block_height == 3);
+    assert!(blockchain.blocks().len() == 3);
+    assert!(blockchain.receipts().len() == 3);
+    assert!(blockchain.balances_of("USDC", &["addr1".into(), "addr2".into()])? == vec![700, 300]);
+    assert!(blockchain.native_balance_of("miner") == 15);
+    assert!(blockchain.balance_history("USDC", "addr2").len() == 3);
+    assert!(blockchain.events().len() == 3 && blockchain.metrics().processed == 3);
+    assert!(blockchain.verify_deterministic());
+
+    // the undone transaction's sequence is free again, so it can be sent again
+    blockchain.process_transaction(
+        Transaction::new("addr1", 100, "USDC", Method::Transfer)
+            .with_seq(4)
+            .with_destination("addr2"),
+    )?;
+    assert!(blockchain.block_height == 4);
+    assert!(blockchain.balances_of("USDC", &["addr1".into(), "addr2".into()])? == vec![600, 400]);
+
+    // fees come back, and changes made outside of blocks before the first undone block stay
+    let mut blockchain = Blockchain::new(vec![Box::new(
+        BasicToken::new("USDC".into(), vec!["addr1".into()], 1000).with_owner("addr1"),
+    )])
+    .with_confirmations_required(2)
+    .with_fee_calculator(Box::new(FlatFee::new(10)));
+    blockchain.credit_native("addr1", 100);
+    let transfer = |seq| {
+        Transaction::new("addr1", 100, "USDC", Method::Transfer)
+            .with_seq(seq)
+            .with_destination("addr2")
+    };
+    blockchain.process_transaction(transfer(1))?;
+    blockchain.batch_mint("USDC", "addr1", vec![("addr3".into(), 50)])?;
+    blockchain.process_transaction(transfer(2))?;
+    assert!(blockchain.native_balance_of("addr1") == 80);
+
+    blockchain.rollback_to_height(1)?;
+    assert!(blockchain.native_balance_of("addr1") == 90);
+    let addresses = ["addr1".into(), "addr2".into(), "addr3".into()];
+    assert!(blockchain.balances_of("USDC", &addresses)? == vec![900, 100, 50]);
+    blockchain.process_transaction(transfer(2))?;
+
+    Ok(())
+}
+
+fn test_process_atomic() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![
+        Box::new(BasicToken::new("USDC".into(), vec!["addr1".into()], 1000)),
+        Box::new(BasicToken::new("WBTC".into(), vec!["addr1".into()], 10)),
+    ]);
+    let seen = Rc::new(RefCell::new(0));
+    let counter = seen.clone();
+    blockchain.subscribe(
+        "USDC",
+        Box::new(move |_: &Event| *counter.borrow_mut() += 1),
+    );
+    let transfer = |contract, seq, amount| {
+        Transaction::new("addr1", amount, contract, Method::Transfer)
+            .with_seq(seq)
+            .with_destination("addr2")
+    };
+    let addresses = ["addr1".to_string(), "addr2".to_string()];
+
+    // the third transfer can't be afforded, so neither token keeps anything
+    let r = blockchain.process_atomic(vec![
+        transfer("USDC", 1, 100),
+        transfer("WBTC", 2, 4),
+        transfer("WBTC", 3, 40),
+    ]);
+    assert!(r == Err(Error::NotEnoughBalance));
+    assert!(blockchain.balances_of("USDC", &addresses)? == vec![1000, 0]);
+    assert!(blockchain.balances_of("WBTC", &addresses)? == vec![10, 0]);
+    assert!(blockchain.block_height == 0);
+    assert!(blockchain.events().is_empty() && blockchain.receipts().is_empty());
+    assert!(blockchain.balance_history("USDC", "addr2").is_empty());
+    assert!(*seen.borrow() == 0);
+
+    // the sequences were given back too
+    let values = blockchain.process_atomic(vec![
+        transfer("USDC", 1, 100),
+        transfer("WBTC", 2, 4),
+        transfer("USDC", 3, 0).with_destination("addr3"),
+    ])?;
+    assert!(values == vec![0, 0, 0]);
+    assert!(blockchain.balances_of("USDC", &addresses)? == vec![900, 100]);
+    assert!(blockchain.balances_of("WBTC", &addresses)? == vec![6, 4]);
+    assert!(blockchain.block_height == 3);
+    assert!(*seen.borrow() == 2);
+
+    Ok(())
+}
+
+fn test_fee_rounding() -> Result<(), Error> {
+    use math::RoundingMode;
+
+    // 2.5% of 1001 is 25.025, and of 1020 exactly 25.5
+    assert!(math::mul_bps_rounded(1001, 250, RoundingMode::Floor) == 25);
+    assert!(math::mul_bps_rounded(1001, 250, RoundingMode::Ceil) == 26);
+    assert!(math::mul_bps_rounded(1001, 250, RoundingMode::Round) == 25);
+    assert!(math::mul_bps_rounded(1020, 250, RoundingMode::Round) == 26);
+    assert!(math::mul_bps_rounded(1000, 250, RoundingMode::Ceil) == 25);
+    assert!(math::mul_bps_rounded(u64::MAX, u16::MAX, RoundingMode::Ceil) == u64::MAX);
+
+    for (rounding, fee) in [
+        (RoundingMode::Floor, 25),
+        (RoundingMode::Ceil, 26),
+        (RoundingMode::Round, 25),
+    ] {
+        let hook = FeeHook::new(250)
+            .with_rounding(rounding)
+            .with_collector("treasury");
+        let mut token = BasicToken::new("USDC".into(), vec!["addr1".into()], 1001)
+            .with_transfer_hook(Box::new(hook));
+        token.transfer("addr1".into(), 1001, "addr2".into())?;
+        assert!(token.balance_of("treasury".into()) == fee);
+        assert!(token.balance_of("addr2".into()) == 1001 - fee);
+    }
+
+    // floor is the default, and without a collector the fee is burnt
+    let mut token = BasicToken::new("USDC".into(), vec!["addr1".into()], 1001)
+        .with_transfer_hook(Box::new(FeeHook::new(250)));
+    token.transfer("addr1".into(), 1001, "addr2".into())?;
+    assert!(token.balances_snapshot() == vec![("addr1".into(), 0), ("addr2".into(), 976)]);
+
+    Ok(())
+}
+
+fn test_counts() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![
+        Box::new(BasicToken::new(
+            "USDC".into(),
+            vec!["addr1".into(), "addr2".into()],
+            1000,
+        )),
+        Box::new(BasicToken::new("WBTC".into(), vec![], 0)),
+    ]);
+    assert!(blockchain.contract_count() == 2);
+    assert!(blockchain.account_count() == 0);
+    assert!(blockchain.total_accounts_with_balance("USDC") == 2);
+    assert!(blockchain.total_accounts_with_balance("WBTC") == 0);
+    assert!(blockchain.total_accounts_with_balance("DAI") == 0);
+
+    blockchain.process_transaction(
+        Transaction::new("addr1", 100, "USDC", Method::Transfer)
+            .with_seq(1)
+            .with_destination("addr3"),
+    )?;
+    blockchain.process_transaction(
+        Transaction::new("addr2", 0, "USDC", Method::TransferAll)
+            .with_seq(1)
+            .with_destination("addr4"),
+    )?;
+    // two senders, and addr2 emptied itself into a new holder
+    assert!(blockchain.account_count() == 2);
+    assert!(blockchain.total_accounts_with_balance("USDC") == 3);
+
+    blockchain.create_account("addr5")?;
+    assert!(blockchain.account_count() == 3);
+
+    Ok(())
+}
+
+fn test_transfer_erc20() -> Result<(), Error> {
+    let mut token = BasicToken::new("USDC".into(), vec!["addr1".into()], 100).with_max_accounts(2);
+
+    assert!(token.transfer_erc20("addr1".into(), 40, "addr2".into(), true)?);
+    assert!(token.transfer_erc20("addr1".into(), 40, "addr2".into(), false)?);
+
+    // strict surfaces the error, lenient reports false, and neither moves anything
+    let r = token.transfer_erc20("addr1".into(), 500, "addr2".into(), true);
+    assert!(r == Err(Error::NotEnoughBalance));
+    assert!(!token.transfer_erc20("addr1".into(), 500, "addr2".into(), false)?);
+    assert!(token.balance_of("addr1".into()) == 20);
+    assert!(token.balance_of("addr2".into()) == 80);
+
+    // any other failure is an error either way
+    let r = token.transfer_erc20("addr1".into(), 10, "addr3".into(), false);
+    assert!(r == Err(Error::AccountLimitReached));
+
+    Ok(())
+}
+
+fn test_batch_mint() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![
+        Box::new(
+            BasicToken::new("USDC".into(), vec!["addr1".into()], 1000)
+                .with_owner("owner")
+                .with_supply_cap(5000),
+        ),
+        Box::new(BasicToken::new("WBTC".into(), vec![], 0)),
+    ]);
+    let addresses = [
+        "addr1".to_string(),
+        "addr2".to_string(),
+        "addr3".to_string(),
+    ];
+
+    blockchain.batch_mint(
+        "USDC",
+        "owner",
+        vec![
+            ("addr1".into(), 500),
+            ("addr2".into(), 250),
+            ("addr1".into(), 1),
+        ],
+    )?;
+    assert!(blockchain.balances_of("USDC", &addresses)? == vec![1501, 250, 0]);
+    assert!(blockchain.events().len() == 3);
+    assert!(
+        blockchain.events()[1]
+            == Event::Mint {
+                contract: "USDC".into(),
+                to: "addr2".into(),
+                amount: 250,
+            }
+    );
+
+    // any failure leaves every balance alone and emits nothing
+    let r = blockchain.batch_mint(
+        "USDC",
+        "owner",
+        vec![("addr3".into(), 10), ("addr2".into(), u64::MAX)],
+    );
+    assert!(r == Err(Error::BalanceOverflow));
+    let r = blockchain.batch_mint(
+        "USDC",
+        "owner",
+        vec![("addr3".into(), 10), ("addr2".into(), 3240)],
+    );
+    assert!(r == Err(Error::SupplyCapExceeded));
+    let r = blockchain.batch_mint("USDC", "addr1", vec![("addr3".into(), 10)]);
+    assert!(r == Err(Error::Unauthorized));
+    // a token without an owner can't be minted at all
+    let r = blockchain.batch_mint("WBTC", "owner", vec![("addr3".into(), 10)]);
+    assert!(r == Err(Error::Unauthorized));
+    assert!(blockchain.balances_of("USDC", &addresses)? == vec![1501, 250, 0]);
+    assert!(blockchain.events().len() == 3);
+
+    // right up to the cap is fine
+    blockchain.batch_mint("USDC", "owner", vec![("addr3".into(), 3249)])?;
+
+    Ok(())
+}
+
+fn test_self_transfer_policy() -> Result<(), Error> {
+    let self_transfer = |seq| {
+        Transaction::new("addr1", 100, "USDC", Method::Transfer)
+            .with_seq(seq)
+            .with_destination("addr1")
+    };
+
+    for policy in [
+        SelfTransferPolicy::Allow,
+        SelfTransferPolicy::Deny,
+        SelfTransferPolicy::NoOp,
+    ] {
+        let taxed = BasicToken::new("USDC".into(), vec!["addr1".into()], 1000)
+            .with_transfer_hook(Box::new(FeeHook::new(1000).with_collector("treasury")));
+        let mut blockchain =
+            Blockchain::new(vec![Box::new(taxed)]).with_self_transfer_policy(policy);
+        let r = blockchain.process_transaction(self_transfer(1));
+        let balances = blockchain.balances_of("USDC", &["addr1".into(), "treasury".into()])?;
+
+        match policy {
+            // a normal transfer, so it pays the fee
+            SelfTransferPolicy::Allow => {
+                assert!(r == Ok(0));
+                assert!(balances == vec![990, 10]);
+                assert!(blockchain.events().len() == 1);
+            }
+            SelfTransferPolicy::Deny => {
+                assert!(r == Err(Error::SelfTransfer));
+                assert!(balances == vec![1000, 0]);
+                assert!(blockchain.block_height == 0);
+            }
+            SelfTransferPolicy::NoOp => {
+                assert!(r == Ok(0));
+                assert!(balances == vec![1000, 0]);
+                assert!(blockchain.events().is_empty());
+                assert!(blockchain.receipts()[0].skipped);
+            }
+        }
+
+        // replaying the chain does the same as processing it did
+        assert!(blockchain.verify_deterministic());
+
+        // the sequence is used up unless the transaction was rejected
+        let replay = blockchain.process_transaction(self_transfer(1));
+        if policy == SelfTransferPolicy::Deny {
+            assert!(replay == Err(Error::SelfTransfer));
+        } else {
+            assert!(replay == Err(Error::BadTransactionSequence));
+        }
+    }
+
+    Ok(())
+}
+
+fn test_genesis_supply() -> Result<(), Error> {
+    let mut token = BasicToken::new("USDC".into(), vec!["addr1".into(), "addr2".into()], 1000)
+        .with_owner("owner");
+    assert!(token.genesis_supply() == 2000);
+    assert!(token.total_supply() == token.genesis_supply());
+
+    token.batch_mint("owner", vec![("addr3".into(), 500)])?;
+    token.transfer("addr1".into(), 300, "addr2".into())?;
+    assert!(token.total_supply() == 2500);
+    assert!(token.genesis_supply() == 2000);
+
+    let token = BasicToken::from_csv("USDC".into(), "addr1,10\naddr2,15")?;
+    assert!(token.genesis_supply() == 25);
+
+    Ok(())
+}
+
+fn test_process_idempotent() -> Result<(), Error> {
+    let keypair = MockKeypair::new(7);
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec![keypair.address()],
+        1000,
+    ))]);
+
+    let transaction = Transaction::new(&keypair.address(), 100, "USDC", Method::Transfer)
+        .with_seq(1)
+        .with_destination("addr2");
+    let transaction = transaction
+        .clone()
+        .with_signature(keypair.sign(&transaction));
+
+    let first = blockchain.process_idempotent(transaction.clone())?;
+    assert!(!first.cached);
+    let second = blockchain.process_idempotent(transaction.clone())?;
+    assert!(second.cached);
+    assert!(
+        second
+            == TransactionReceipt {
+                cached: true,
+                ..first
+            }
+    );
+    // it only ran once
+    assert!(blockchain.block_height == 1);
+    assert!(blockchain.balances_of("USDC", &["addr2".into()])? == vec![100]);
+
+    // without the cache the duplicate is a replay
+    assert!(blockchain.process_transaction(transaction) == Err(Error::BadTransactionSequence));
+
+    Ok(())
+}
+
+fn test_balance_histogram() -> Result<(), Error> {
+    let token = BasicToken::from_csv(
+        "USDC".into(),
+        "addr1,5\naddr2,10\naddr3,99\naddr4,100\naddr5,5000\naddr6,0",
+    )?;
+
+    // addr1 is below the first bucket, addr2 and addr4 sit on a boundary, addr5 is past
+    // the top one, and addr6 holds nothing
+    assert!(token.balance_histogram(&[10, 100, 1000]) == vec![2, 1, 1]);
+    assert!(token.balance_histogram(&[1]) == vec![5]);
+    assert!(token.balance_histogram(&[]).is_empty());
+
+    Ok(())
+}
+
+fn test_error_precedence() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into()],
+        1000,
+    ))]);
+    blockchain.blacklist("bad");
+    blockchain
+        .process_transaction(Transaction::new("addr1", 0, "USDC", Method::BalanceOf).with_seq(1))?;
+
+    let transfer = |contract, seq, destination| {
+        Transaction::new("addr1", 5000, contract, Method::Transfer)
+            .with_seq(seq)
+            .with_destination(destination)
+    };
+    let forged = |transaction: Transaction| transaction.with_signature(vec![0; 16]);
+
+    // a replayed, badly signed, unaffordable transfer fails on the first group that fails,
+    // and none of these use up the sequence
+    let rejected = [
+        (
+            forged(transfer("WBTC", 1, "bad")).with_valid_until(0),
+            Error::Blacklisted,
+        ),
+        (
+            forged(transfer("WBTC", 1, "addr2")).with_valid_until(0),
+            Error::TransactionExpired,
+        ),
+        (
+            forged(transfer("WBTC", 1, "addr2")),
+            Error::ContractNotFound,
+        ),
+        (
+            forged(transfer("USDC", 1, "addr2")),
+            Error::InvalidSignature,
+        ),
+        (transfer("USDC", 1, "addr2"), Error::BadTransactionSequence),
+    ];
+    for (transaction, error) in rejected {
+        assert!(blockchain.check_transaction(&transaction) == Err(error));
+        assert!(blockchain.process_transaction(transaction) == Err(error));
+    }
+
+    // only once everything else passes does the balance matter
+    let unaffordable = transfer("USDC", 2, "addr2");
+    blockchain.check_transaction(&unaffordable)?;
+    assert!(blockchain.process_transaction(unaffordable) == Err(Error::NotEnoughBalance));
+
+    Ok(())
+}
+
+fn test_property_harness() -> Result<(), Error> {
+    let blockchain = Blockchain::new(vec![
+        Box::new(BasicToken::new(
+            "USDC".into(),
+            vec!["addr1".into(), "addr2".into()],
+            1000,
+        )),
+        Box::new(BasicToken::new(
+            "WBTC".into(),
+            vec!["addr3".into()],
+            u64::MAX,
+        )),
+    ]);
+    let mut harness = harness::PropertyHarness::new(blockchain);
+    let transfer = |sender, seq, amount, contract, to| {
+        Transaction::new(sender, amount, contract, Method::Transfer)
+            .with_seq(seq)
+            .with_destination(to)
+    };
+
+    let succeeded = harness.run(vec![
+        transfer("addr1", 1, 600, "USDC", "addr2"),
+        // replays, immediately and later on
+        transfer("addr1", 1, 600, "USDC", "addr2"),
+        transfer("addr2", 5, 1600, "USDC", "addr4"),
+        transfer("addr2", 3, 1, "USDC", "addr4"),
+        // overdrafts, by one and by everything
+        transfer("addr1", 6, 401, "USDC", "addr2"),
+        transfer("addr1", 7, u64::MAX, "USDC", "addr2"),
+        // the largest possible balance moving around
+        transfer("addr3", 1, u64::MAX, "WBTC", "addr1"),
+        transfer("addr1", 8, u64::MAX, "WBTC", "addr1"),
+        Transaction::new("addr1", 0, "WBTC", Method::TransferAll)
+            .with_seq(9)
+            .with_destination("addr3"),
+        // zero amounts, missing contracts and sending to a contract
+        transfer("addr4", 1, 0, "USDC", "addr5"),
+        transfer("addr4", 2, 10, "DAI", "addr5"),
+        transfer("addr4", 3, 1, "USDC", "WBTC"),
+    ]);
+    // the first transfer, addr2's sequence 5, addr3's transfer and the self-transfer,
+    // the transfer all, and addr4's zero transfer
+    assert!(succeeded == Ok(6));
+    assert!(harness.blockchain().block_height == 6);
+    assert!(
+        harness
+            .blockchain()
+            .balances_of("USDC", &["addr4".into()])?
+            == vec![1600]
+    );
+
+    Ok(())
+}
+
+fn test_storage_capacity() -> Result<(), Error> {
+    // sized up front or not, and whichever hasher storage::Map uses, the chain behaves
+    // the same
+    let holders: Vec<String> = (0..200).map(|i| format!("addr{}", i)).collect();
+    let build = |sized: bool| {
+        let token = if sized {
+            BasicToken::with_capacity("USDC".into(), holders.clone(), 10, 400)
+        } else {
+            BasicToken::new("USDC".into(), holders.clone(), 10)
+        };
+        if sized {
+            Blockchain::with_capacity(vec![Box::new(token)], 200)
+        } else {
+            Blockchain::new(vec![Box::new(token)])
+        }
+    };
+
+    let mut dumps = Vec::new();
+    for sized in [false, true] {
+        let mut blockchain = build(sized);
+        for (i, holder) in holders.iter().enumerate() {
+            blockchain.process_transaction(
+                Transaction::new(holder, 5, "USDC", Method::Transfer)
+                    .with_seq(1)
+                    .with_destination(&format!("new{}", i % 7)),
+            )?;
+        }
+        assert!(blockchain.account_count() == 200);
+        assert!(blockchain.total_accounts_with_balance("USDC") == 207);
+        assert!(blockchain.export_sequences().len() == 200);
+        assert!(blockchain.verify_deterministic());
+        dumps.push((blockchain.dump_state(), blockchain.state_root()));
+    }
+    assert!(dumps[0] == dumps[1]);
+
+    Ok(())
+}
+
+fn test_export_accounts() -> Result<(), Error> {
+    let source = BasicToken::from_csv("USDC".into(), "addr1,100\naddr2,250\naddr3,7")?;
+    let exported = source.export_accounts(&["addr2".into(), "addr1".into()]);
+    assert!(exported == vec![("addr2".into(), 250), ("addr1".into(), 100)]);
+
+    let mut shard = BasicToken::from_csv("USDC".into(), "addr1,1")?;
+    shard.import_accounts(exported)?;
+    assert!(shard.balances_snapshot() == vec![("addr1".into(), 101), ("addr2".into(), 250)]);
+
+    // an overflow anywhere leaves the ledger alone
+    let r = shard.import_accounts(vec![("addr3".into(), 5), ("addr2".into(), u64::MAX)]);
+    assert!(r == Err(Error::BalanceOverflow));
+    assert!(shard.balances_snapshot() == vec![("addr1".into(), 101), ("addr2".into(), 250)]);
+
+    Ok(())
+}
+
+fn test_min_gas_price() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into(), "addr2".into()],
+        1000,
+    ))])
+    .with_min_gas_price(5);
+    blockchain.credit_native("addr1", 1_000_000);
+    let transfer = |gas_price| {
+        Transaction::new("addr1", 1, "USDC", Method::Transfer)
+            .with_seq(1)
+            .with_destination("addr2")
+            .with_gas(50_000, gas_price)
+    };
+
+    // rejected before anything is charged or the sequence is used
+    assert!(blockchain.process(transfer(4)) == Err(Error::GasPriceTooLow));
+    assert!(blockchain.native_balance_of("addr1") == 1_000_000);
+    blockchain.process(transfer(5))?;
+    assert!(blockchain.native_balance_of("addr1") == 1_000_000 - 26_000 * 5);
+
+    Ok(())
+}
+
+fn test_history_by_category() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into(), "addr2".into()],
+        1000,
+    ))]);
+    let transfer = |sender, seq, category| {
+        Transaction::new(sender, 10, "USDC", Method::Transfer)
+            .with_seq(seq)
+            .with_destination("addr3")
+            .with_category(category)
+    };
+
+    blockchain.process_transaction(transfer("addr1", 1, "swap"))?;
+    blockchain.process_transaction(transfer("addr1", 2, "payment"))?;
+    blockchain.process_transaction(transfer("addr2", 1, "swap"))?;
+    blockchain.process_transaction(transfer("addr1", 3, "swap"))?;
+    blockchain
+        .process_transaction(Transaction::new("addr1", 0, "USDC", Method::BalanceOf).with_seq(4))?;
+
+    let swaps = blockchain.history_by_category("addr1", "swap");
+    assert!(swaps.iter().map(|r| r.sequence).collect::<Vec<_>>() == vec![1, 3]);
+    assert!(swaps[0].matches(
+        &ReceiptBuilder::new("addr1", Method::Transfer)
+            .sequence(1)
+            .block_height(1)
+            .contract("USDC")
+            .category("swap")
+            .build()
+    ));
+    assert!(blockchain.history_by_category("addr1", "payment").len() == 1);
+    assert!(blockchain
+        .history_by_category("addr2", "payment")
+        .is_empty());
+
+    // the category doesn't change what gets signed
+    assert!(transfer("addr1", 5, "swap").hash() == transfer("addr1", 5, "payment").hash());
+
+    Ok(())
+}
+
+fn test_balance_changes() -> Result<(), Error> {
+    let taxed = BasicToken::new("WBTC".into(), vec!["addr1".into()], 1000)
+        .with_transfer_hook(Box::new(FeeHook::new(1000).with_collector("treasury")))
+        .with_owner("owner");
+    let mut blockchain = Blockchain::new(vec![
+        Box::new(BasicToken::new("USDC".into(), vec!["addr1".into()], 1000)),
+        Box::new(taxed),
+    ]);
+    let change = |contract: &str, address: &str, old, new, height| BalanceChange {
+        contract: contract.into(),
+        address: address.into(),
+        old,
+        new,
+        height,
+    };
+
+    blockchain.process_transaction(
+        Transaction::new("addr1", 100, "USDC", Method::Transfer)
+            .with_seq(1)
+            .with_destination("addr2"),
+    )?;
+    assert!(
+        blockchain.balance_changes()
+            == vec![
+                change("USDC", "addr1", 1000, 900, 1),
+                change("USDC", "addr2", 0, 100, 1),
+            ]
+    );
+
+    // the fee the hook withholds shows up too, and mints outside a block are included
+    blockchain.process_transaction(
+        Transaction::new("addr1", 100, "WBTC", Method::Transfer)
+            .with_seq(2)
+            .with_destination("addr2"),
+    )?;
+    blockchain.batch_mint("WBTC", "owner", vec![("addr2".into(), 5)])?;
+    assert!(
+        blockchain.balance_changes()[2..]
+            == [
+                change("WBTC", "addr1", 1000, 900, 2),
+                change("WBTC", "addr2", 0, 90, 2),
+                change("WBTC", "treasury", 0, 10, 2),
+                change("WBTC", "addr2", 90, 95, 2),
+            ]
+    );
+    assert!(blockchain.balance_history("WBTC", "treasury") == vec![(2, 10)]);
+
+    // a balance check changes nothing
+    blockchain
+        .process_transaction(Transaction::new("addr1", 0, "USDC", Method::BalanceOf).with_seq(3))?;
+    assert!(blockchain.balance_changes().len() == 6);
+
+    Ok(())
+}
+
+fn test_max_sequence_gap() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into()],
+        1000,
+    ))])
+    .with_max_sequence_gap(10);
+    let balance_of = |seq| Transaction::new("addr1", 0, "USDC", Method::BalanceOf).with_seq(seq);
+
+    // up to 10 ahead of the current sequence is fine
+    blockchain.process_transaction(balance_of(10))?;
+    let r = blockchain.process_transaction(balance_of(21));
+    assert!(r == Err(Error::SequenceGapTooLarge));
+    blockchain.process_transaction(balance_of(20))?;
+
+    // the default gap
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into()],
+        1000,
+    ))]);
+    let r = blockchain.process_transaction(balance_of(DEFAULT_MAX_SEQUENCE_GAP + 1));
+    assert!(r == Err(Error::SequenceGapTooLarge));
+    blockchain.process_transaction(balance_of(DEFAULT_MAX_SEQUENCE_GAP))?;
+
+    Ok(())
+}
+
+fn test_explain() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into()],
+        1000,
+    ))]);
+    let transfer = Transaction::new("addr1", 100, "USDC", Method::Transfer)
+        .with_seq(1)
+        .with_destination("addr2");
+
+    let before = blockchain.dump_state();
+    assert!(
+        blockchain.explain(&transfer)
+            == "addr1 sends 100 USDC to addr2; addr1 balance 1000→900; addr2 balance 0→100"
+    );
+    assert!(blockchain.dump_state() == before && blockchain.block_height == 0);
+
+    let simulation = blockchain.simulate(&transfer)?;
+    assert!(simulation.transferred == Some(100) && simulation.changes[0].height == 1);
+
+    let balance_of = Transaction::new("addr1", 0, "USDC", Method::BalanceOf).with_seq(1);
+    assert!(blockchain.explain(&balance_of) == "addr1 holds 1000 USDC");
+    let skipped = Transaction::new("addr1", 100, "USDC", Method::ConditionalTransfer)
+        .with_seq(1)
+        .with_destination("addr2")
+        .with_condition(5000);
+    assert!(blockchain.explain(&skipped) == "addr1's conditional_transfer is skipped");
+
+    // failures are explained too, whether from validation or from executing
+    blockchain.process_transaction(transfer.clone())?;
+    assert!(
+        blockchain.explain(&transfer) == "addr1's transfer would fail with BadTransactionSequence"
+    );
+    let too_much = Transaction::new("addr1", 5000, "USDC", Method::Transfer)
+        .with_seq(2)
+        .with_destination("addr2");
+    assert!(blockchain.explain(&too_much) == "addr1's transfer would fail with NotEnoughBalance");
+
+    Ok(())
+}
+
+fn test_volume_cap() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![
+        Box::new(BasicToken::new("USDC".into(), vec!["addr1".into()], 1000)),
+        Box::new(BasicToken::new("WBTC".into(), vec!["addr1".into()], 1000)),
+    ])
+    .with_volume_cap(500, 3);
+    let mut seq = 0;
+    let mut transfer = |contract, method, amount| {
+        seq += 1;
+        Transaction::new("addr1", amount, contract, method)
+            .with_seq(seq)
+            .with_destination("addr2")
+    };
+
+    // block 3 would take blocks 1..3 over the cap, which simulating it predicts
+    blockchain.process_transaction(transfer("USDC", Method::Transfer, 200))?;
+    blockchain.process_transaction(transfer("USDC", Method::Transfer, 300))?;
+    let over = transfer("USDC", Method::Transfer, 1);
+    assert!(blockchain.simulate(&over).err() == Some(Error::VolumeCapExceeded));
+    let r = blockchain.process_transaction(over);
+    assert!(r == Err(Error::VolumeCapExceeded));
+    // amounts worked out during execution count too, and nothing moves when they're over
+    let r = blockchain.process_transaction(transfer("USDC", Method::TransferAll, 0));
+    assert!(r == Err(Error::VolumeCapExceeded));
+    assert!(blockchain.balances_of("USDC", &["addr1".into(), "addr2".into()])? == vec![500, 500]);
+    assert!(blockchain.block_height == 2);
+    // each token has a cap of its own
+    blockchain.process_transaction(transfer("WBTC", Method::Transfer, 500))?;
+
+    // block 4's window starts at block 2, so only the 300 still counts
+    let r = blockchain.process_transaction(transfer("USDC", Method::Transfer, 201));
+    assert!(r == Err(Error::VolumeCapExceeded));
+    blockchain.process_transaction(transfer("USDC", Method::Transfer, 200))?;
+
+    // and once the window has passed everything, the full cap is back
+    for _ in 0..3 {
+        blockchain.produce_block();
+    }
+    assert!(blockchain.process_transaction(transfer("USDC", Method::TransferAll, 0))? == 300);
+    assert!(blockchain.balances_of("USDC", &["addr1".into(), "addr2".into()])? == vec![0, 1000]);
+
+    // a window as long as the chain never lets a transfer go
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into()],
+        1000,
+    ))])
+    .with_volume_cap(500, u64::MAX);
+    let mut seq = 0;
+    let mut transfer = |amount| {
+        seq += 1;
+        Transaction::new("addr1", amount, "USDC", Method::Transfer)
+            .with_seq(seq)
+            .with_destination("addr2")
+    };
+    blockchain.process_transaction(transfer(500))?;
+    for _ in 0..10 {
+        blockchain.produce_block();
+    }
+    assert!(blockchain.process_transaction(transfer(1)) == Err(Error::VolumeCapExceeded));
+
+    Ok(())
+}
+
+fn test_archive() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into()],
+        1000,
+    ))]);
+    let mut seq = 0;
+    let mut transfer = |amount| {
+        seq += 1;
+        Transaction::new("addr1", amount, "USDC", Method::Transfer)
+            .with_seq(seq)
+            .with_destination("addr2")
+    };
+
+    // nothing is kept until archiving is on
+    blockchain.process_transaction(transfer(100))?;
+    assert!(blockchain.balance_at("USDC", "addr1", 1) == Err(Error::NotArchived));
+
+    blockchain.set_archive(true);
+    blockchain.process_transaction(transfer(200))?;
+    blockchain.produce_block();
+    blockchain.process_transaction(transfer(300))?;
+    assert!(blockchain.balance_at("USDC", "addr1", 1)? == 900);
+    assert!(blockchain.balance_at("USDC", "addr1", 2)? == 700);
+    assert!(blockchain.balance_at("USDC", "addr2", 3)? == 300);
+    assert!(blockchain.balance_at("USDC", "addr1", 4)? == 400);
+    assert!(blockchain.balance_at("USDC", "addr9", 4)? == 0);
+    assert!(blockchain.balance_at("USDC", "addr1", 5) == Err(Error::BlockNotFound));
+    assert!(blockchain.balance_at("WBTC", "addr1", 4) == Err(Error::ContractNotFound));
+
+    // and it's all dropped once archiving is off again
+    blockchain.set_archive(false);
+    blockchain.process_transaction(transfer(1))?;
+    assert!(blockchain.balance_at("USDC", "addr1", 4) == Err(Error::NotArchived));
+
+    // from genesis
+    let blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into()],
+        1000,
+    ))])
+    .with_archive(true);
+    assert!(blockchain.balance_at("USDC", "addr1", 0)? == 1000);
+
+    Ok(())
+}
+
+fn test_valid_from() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into()],
+        1000,
+    ))]);
+    let scheduled = Transaction::new("addr1", 100, "USDC", Method::Transfer)
+        .with_seq(1)
+        .with_destination("addr2")
+        .with_valid_from(2);
+
+    // too early, and that doesn't use up the sequence
+    blockchain.produce_block();
+    let r = blockchain.process_transaction(scheduled.clone());
+    assert!(r == Err(Error::TransactionNotYetValid));
+
+    // valid from exactly its height on
+    blockchain.produce_block();
+    blockchain.process_transaction(scheduled.clone())?;
+    assert!(blockchain.balances_of("USDC", &["addr2".into()])? == vec![100]);
+
+    // the scheduled height is signed over
+    assert!(scheduled.hash() != scheduled.clone().with_valid_from(3).hash());
+
+    Ok(())
+}
+
+fn test_relay() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into()],
+        1000,
+    ))])
+    .with_fee_calculator(Box::new(FlatFee::new(10)));
+    blockchain.credit_native("relayer", 100_000);
+    let transfer = |seq| {
+        Transaction::new("addr1", 100, "USDC", Method::Transfer)
+            .with_seq(seq)
+            .with_destination("addr2")
+            .with_gas(50_000, 1)
+            .with_tip(5)
+    };
+
+    // addr1 has no native balance, so can't pay for itself
+    assert!(blockchain.process(transfer(1)) == Err(Error::InsufficientFeeBalance));
+
+    // the relayer pays for the gas used, the fee and the tip, and the tokens still come
+    // from addr1
+    blockchain.relay(transfer(1), "relayer")?;
+    assert!(blockchain.native_balance_of("relayer") == 100_000 - 46_000 - 10 - 5);
+    assert!(blockchain.native_balance_of("addr1") == 0);
+    assert!(blockchain.balances_of("USDC", &["addr1".into(), "addr2".into()])? == vec![900, 100]);
+    assert!(blockchain.receipts()[0].sender == "addr1");
+
+    // a relayer that can't cover the gas limit doesn't get the transaction through, and
+    // the sequence is still there for one that can
+    let r = blockchain.relay(transfer(2), "broke");
+    assert!(r == Err(Error::InsufficientFeeBalance));
+    blockchain.relay(transfer(2), "relayer")?;
+    assert!(blockchain.balances_of("USDC", &["addr1".into(), "addr2".into()])? == vec![800, 200]);
+
+    Ok(())
+}
+
+fn test_nonce_fillers() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into()],
+        1000,
+    ))]);
+    blockchain
+        .process_transaction(Transaction::new("addr1", 0, "USDC", Method::BalanceOf).with_seq(2))?;
+
+    let fillers = blockchain.generate_nonce_fillers("addr1", 5);
+    assert!(fillers.iter().map(|t| t.sequence).collect::<Vec<_>>() == vec![3, 4, 5]);
+    assert!(blockchain.generate_nonce_fillers("addr1", 2).is_empty());
+    assert!(blockchain.generate_nonce_fillers("addr2", 1).len() == 1);
+
+    // they all go through without moving anything
+    let before = blockchain.balances_of("USDC", &["addr1".into()])?;
+    for filler in fillers {
+        blockchain.process_transaction(filler)?;
+    }
+    assert!(blockchain.balances_of("USDC", &["addr1".into()])? == before);
+    assert!(
+        blockchain.is_replay(&Transaction::new("addr1", 0, "USDC", Method::BalanceOf).with_seq(5))
+    );
+
+    // there's no sequence after the last one to fill
+    let blockchain = Blockchain::new(vec![]).with_genesis_nonce(u64::MAX);
+    assert!(blockchain
+        .generate_nonce_fillers("addr1", u64::MAX)
+        .is_empty());
+
+    Ok(())
+}
+
+fn test_noop() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into()],
+        1000,
+    ))]);
+    let noop = Transaction::new("addr1", 500, "", Method::Noop)
+        .with_seq(1)
+        .with_destination("addr2");
+    let before = blockchain.dump_state();
+
+    assert!(blockchain.simulate(&noop)?.changes.is_empty());
+    assert!(blockchain.explain(&noop) == "addr1 uses up sequence 1");
+    assert!(blockchain.process_transaction(noop.clone())? == 0);
+    assert!(blockchain.block_height == 1);
+    assert!(blockchain.is_replay(&noop));
+    assert!(blockchain.balances_of("USDC", &["addr1".into(), "addr2".into()])? == vec![1000, 0]);
+    assert!(blockchain.events().is_empty() && blockchain.receipts()[0].method == Method::Noop);
+    assert!(blockchain.dump_state() != before);
+
+    assert!(blockchain.process_transaction(noop) == Err(Error::BadTransactionSequence));
+    assert!(blockchain.block_height == 1);
+    assert!(blockchain.verify_deterministic());
+
+    Ok(())
+}
+
+fn test_whitelist() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into(), "addr2".into()],
+        1000,
+    ))]);
+    let transfer = |sender, seq| {
+        Transaction::new(sender, 10, "USDC", Method::Transfer)
+            .with_seq(seq)
+            .with_destination("addr3")
+    };
+
+    // everyone may send while it's off, whitelisted or not
+    blockchain.whitelist("addr1");
+    blockchain.process_transaction(transfer("addr1", 1))?;
+    blockchain.process_transaction(transfer("addr2", 1))?;
+
+    blockchain.set_whitelist_mode(true);
+    blockchain.process_transaction(transfer("addr1", 2))?;
+    let r = blockchain.process_transaction(transfer("addr2", 2));
+    assert!(r == Err(Error::NotWhitelisted));
+    // receiving doesn't need a whitelisting
+    assert!(blockchain.balances_of("USDC", &["addr3".into()])? == vec![30]);
+
+    blockchain.whitelist("addr2");
+    blockchain.unwhitelist("addr1");
+    blockchain.process_transaction(transfer("addr2", 2))?;
+    let r = blockchain.process_transaction(transfer("addr1", 3));
+    assert!(r == Err(Error::NotWhitelisted));
+
+    blockchain.set_whitelist_mode(false);
+    blockchain.process_transaction(transfer("addr1", 3))?;
+
+    Ok(())
+}
+
+fn test_receipt_contract() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![
+        Box::new(BasicToken::new("USDC".into(), vec!["addr1".into()], 1000)),
+        Box::new(BasicToken::new("WBTC".into(), vec!["addr1".into()], 1000)),
+    ]);
+    let transfer = |contract, seq| {
+        Transaction::new("addr1", 10, contract, Method::Transfer)
+            .with_seq(seq)
+            .with_destination("addr2")
+    };
+
+    blockchain.process_transaction(transfer("WBTC", 1))?;
+    blockchain.process_transaction(transfer("USDC", 2))?;
+    blockchain.process_transaction(Transaction::new("addr1", 0, "", Method::Noop).with_seq(3))?;
+
+    let contracts: Vec<Option<&str>> = blockchain
+        .receipts()
+        .iter()
+        .map(|r| r.contract.as_deref())
+        .collect();
+    assert!(contracts == vec![Some("WBTC"), Some("USDC"), None]);
+
+    Ok(())
+}
+
+fn test_arithmetic_mode() -> Result<(), Error> {
+    use math::ArithmeticMode;
+
+    for mode in [
+        ArithmeticMode::Checked,
+        ArithmeticMode::Saturating,
+        ArithmeticMode::Wrapping,
+    ] {
+        let mut token =
+            BasicToken::from_csv("USDC".into(), "addr1,100\naddr2,18446744073709551610")?
+                .with_arithmetic_mode(mode)
+                .with_owner("owner");
+        let transferred = token.transfer("addr1".into(), 10, "addr2".into());
+        let minted = token.batch_mint("owner", vec![("addr2".into(), 10)]);
+        let balances = (
+            token.balance_of("addr1".into()),
+            token.balance_of("addr2".into()),
+        );
+
+        match mode {
+            // both fail, leaving every balance alone
+            ArithmeticMode::Checked => {
+                assert!(transferred == Err(Error::BalanceOverflow));
+                assert!(minted == Err(Error::BalanceOverflow));
+                assert!(balances == (100, u64::MAX - 5));
+            }
+            ArithmeticMode::Saturating => {
+                assert!(transferred.is_ok() && minted.is_ok());
+                assert!(balances == (90, u64::MAX));
+            }
+            // 5 past the max is 4, and another 10 is 14
+            ArithmeticMode::Wrapping => {
+                assert!(transferred.is_ok() && minted.is_ok());
+                assert!(balances == (90, 14));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn test_derive_contract_address() -> Result<(), Error> {
+    let address = Blockchain::derive_contract_address("addr1", 1);
+    assert!(address == Blockchain::derive_contract_address("addr1", 1));
+    assert!(address.starts_with("0x") && address.len() == 18);
+
+    let mut addresses = HashSet::new();
+    for deployer in ["addr1", "addr2"] {
+        for nonce in 0..100 {
+            addresses.insert(Blockchain::derive_contract_address(deployer, nonce));
+        }
+    }
+    assert!(addresses.len() == 200);
+
+    Ok(())
+}
+
+fn test_deploy() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![]).with_deploy_fee(500);
+    let deploy = Transaction::new("addr1", 0, "", Method::Deploy)
+        .with_seq(1)
+        .with_deploy(
+            "Mock Dollar",
+            vec![("addr1".into(), 1000), ("addr2".into(), 50)],
+        );
+
+    // the deployer can't afford the fee, and keeps its sequence
+    assert!(blockchain.check_transaction(&deploy) == Err(Error::InsufficientFeeBalance));
+    assert!(blockchain.process_transaction(deploy.clone()) == Err(Error::InsufficientFeeBalance));
+    assert!(blockchain.contract_count() == 0);
+
+    blockchain.credit_native("addr1", 600);
+    blockchain.process_transaction(deploy)?;
+    assert!(blockchain.native_balance_of("addr1") == 100);
+    let address = Blockchain::derive_contract_address("addr1", 1);
+    assert!(blockchain.receipts()[0].contract == Some(address.clone()));
+    assert!(blockchain.contract_addresses() == vec![address.clone()]);
+
+    blockchain.process_transaction(
+        Transaction::new("addr1", 300, &address, Method::Transfer)
+            .with_seq(2)
+            .with_destination("addr2"),
+    )?;
+    let balance = Transaction::new("addr2", 0, &address, Method::BalanceOf).with_seq(1);
+    assert!(blockchain.process_transaction(balance)? == 350);
+
+    // replaying the blocks deploys the token again, without charging the fee
+    assert!(blockchain.verify_deterministic());
+
+    // a deploy to an address that's taken is rejected before it's charged or uses its
+    // sequence
+    let taken = Blockchain::derive_contract_address("addr1", 1);
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        taken,
+        vec!["addr2".into()],
+        1000,
+    ))])
+    .with_deploy_fee(500);
+    blockchain.credit_native("addr1", 600);
+    let deploy = |seq| {
+        Transaction::new("addr1", 0, "", Method::Deploy)
+            .with_seq(seq)
+            .with_deploy("Mock Dollar", vec![("addr1".into(), 1000)])
+    };
+    assert!(blockchain.check_transaction(&deploy(1)) == Err(Error::AccountExists));
+    assert!(blockchain.process_transaction(deploy(1)) == Err(Error::AccountExists));
+    assert!(blockchain.native_balance_of("addr1") == 600 && blockchain.block_height == 0);
+    blockchain.process_transaction(deploy(2))?;
+    assert!(blockchain.contract_count() == 2);
+
+    Ok(())
+}
+
+fn test_events_by_topic() -> Result<(), Error> {
+    let holders: Vec<String> = (0..10).map(|i| format!("addr{}", i)).collect();
+    let mut blockchain = Blockchain::new(vec![Box::new(
+        BasicToken::new("USDC".into(), holders.clone(), 1000).with_owner("addr0"),
+    )]);
+
+    let mut sequences = vec![0; holders.len()];
+    for i in 0..100 {
+        let (from, to) = (i % holders.len(), (i * 7 + 3) % holders.len());
+        sequences[from] += 1;
+        blockchain.process_transaction(
+            Transaction::new(&holders[from], 1, "USDC", Method::Transfer)
+                .with_seq(sequences[from])
+                .with_destination(&holders[to]),
+        )?;
+    }
+    blockchain.batch_mint(
+        "USDC",
+        "addr0",
+        vec![("addr3".into(), 10), ("addr4".into(), 10)],
+    )?;
+
+    let transfers = blockchain.events_by_topic("transfer");
+    assert!(transfers.len() == 100);
+    assert!(blockchain.events_by_topic("mint").len() == 2);
+
+    // an address matches as sender or recipient, listed once even if it's both
+    let involving = |event: &&Event| match event {
+        Event::Transfer { from, to, .. } => from == "addr3" || to == "addr3",
+        Event::Mint { to, .. } => to == "addr3",
+        Event::Burn { from, .. } => from == "addr3",
+        Event::SequenceResync { address, .. } => address == "addr3",
+    };
+    let expected: Vec<&Event> = blockchain.events().iter().filter(involving).collect();
+    assert!(blockchain.events_by_topic("addr3") == expected);
+    assert!(blockchain.events_by_topic("nobody").is_empty());
+
+    Ok(())
+}
+
+fn test_advance_time() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![]).with_block_time(10);
+
+    // two and a half block times produce two blocks, and the half carries over
+    assert!(blockchain.advance_time(25) == 2);
+    assert!(blockchain.block_height == 2 && blockchain.blocks().len() == 2);
+    assert!(blockchain.advance_time(4) == 0);
+    assert!(blockchain.advance_time(1) == 1);
+    assert!(blockchain.block_height == 3);
+
+    Ok(())
+}
+
+#[cfg(feature = "test-utils")]
+fn test_set_balance() -> Result<(), Error> {
+    let mut token = BasicToken::new("USDC".into(), vec!["addr1".into(), "addr2".into()], 1000);
+    token.set_balance("addr1", 40);
+    token.set_balance("addr3", 7);
+    assert!(token.balance_of("addr1".into()) == 40 && token.balance_of("addr3".into()) == 7);
+    assert!(token.total_supply() == 1047 && token.genesis_supply() == 2000);
+
+    let mut blockchain = Blockchain::new(vec![Box::new(token)]);
+    let transfer = Transaction::new("addr1", 50, "USDC", Method::Transfer)
+        .with_seq(1)
+        .with_destination("addr3");
+    assert!(blockchain.process_transaction(transfer) == Err(Error::NotEnoughBalance));
+    blockchain.process_transaction(
+        Transaction::new("addr1", 30, "USDC", Method::Transfer)
+            .with_seq(2)
+            .with_destination("addr3"),
+    )?;
+    let holders = blockchain.top_holders("USDC", usize::MAX)?;
+    assert!(holders.iter().map(|(_, balance)| balance).sum::<u64>() == 1047);
+
+    Ok(())
+}
+
+fn test_recipient_allowlist() -> Result<(), Error> {
+    let mut token = BasicToken::new("USDC".into(), vec!["addr1".into()], 1000)
+        .with_owner("owner")
+        .with_recipient_allowlist();
+    assert!(token.allow_recipient("addr1", "addr2") == Err(Error::Unauthorized));
+    token.allow_recipient("owner", "addr2")?;
+    let mut blockchain = Blockchain::new(vec![Box::new(token)]);
+
+    let transfer = |seq: u64, to: &str| {
+        Transaction::new("addr1", 100, "USDC", Method::Transfer)
+            .with_seq(seq)
+            .with_destination(to)
+    };
+    blockchain.process_transaction(transfer(1, "addr2"))?;
+    assert!(
+        blockchain.process_transaction(transfer(2, "addr3")) == Err(Error::RecipientNotAllowed)
+    );
+    let balance = Transaction::new("addr1", 0, "USDC", Method::BalanceOf).with_seq(3);
+    assert!(blockchain.process_transaction(balance)? == 900);
+
+    // minting goes to anyone
+    blockchain.batch_mint("USDC", "owner", vec![("addr3".into(), 50)])?;
+    let balance = Transaction::new("addr3", 0, "USDC", Method::BalanceOf).with_seq(1);
+    assert!(blockchain.process_transaction(balance)? == 50);
+
+    // without the allowlist on, the owner has no list to manage
+    let mut open = BasicToken::new("DAI".into(), vec![], 0).with_owner("owner");
+    assert!(open.allow_recipient("owner", "addr2") == Err(Error::Unauthorized));
+
+    Ok(())
+}
+
+fn test_bridge() -> Result<(), Error> {
+    let mut source = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into()],
+        1000,
+    ))]);
+    let mut dest = Blockchain::new(vec![Box::new(
+        BasicToken::new("wUSDC".into(), vec![], 0)
+            .with_owner("bridge")
+            .with_supply_cap(500),
+    )]);
+    let bridge = bridge::Bridge::new("escrow", "bridge").with_route("USDC", "wUSDC");
+
+    bridge.lock_and_mint(&mut source, &mut dest, "USDC", "addr1", 300, "addr2")?;
+    // over the wrapped token's cap, so the mint fails and the lock is undone
+    let over = bridge.lock_and_mint(&mut source, &mut dest, "USDC", "addr1", 300, "addr2");
+    assert!(over == Err(Error::SupplyCapExceeded));
+    assert!(
+        bridge.lock_and_mint(&mut source, &mut dest, "DAI", "addr1", 1, "addr2")
+            == Err(Error::ContractNotFound)
+    );
+
+    let balances = |chain: &Blockchain, token: &str| -> Result<HashMap<String, u64>, Error> {
+        Ok(chain.top_holders(token, usize::MAX)?.into_iter().collect())
+    };
+    let locked = balances(&source, "USDC")?;
+    let minted = balances(&dest, "wUSDC")?;
+    assert!(locked["addr1"] == 700 && locked["escrow"] == 300);
+    assert!(minted["addr2"] == 300 && minted.values().sum::<u64>() == locked["escrow"]);
+    assert!(locked.values().sum::<u64>() == 1000);
+
+    Ok(())
+}
+
+fn test_next_valid_sequence() -> Result<(), Error> {
+    for mode in [SequenceMode::Monotonic, SequenceMode::Strict] {
+        let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+            "USDC".into(),
+            vec!["addr1".into()],
+            1000,
+        ))])
+        .with_sequence_mode(mode)
+        .with_genesis_nonce(10);
+        assert!(blockchain.next_valid_sequence("addr1") == 11);
+
+        let balance =
+            |seq: u64| Transaction::new("addr1", 0, "USDC", Method::BalanceOf).with_seq(seq);
+        blockchain.process_transaction(balance(blockchain.next_valid_sequence("addr1")))?;
+        assert!(blockchain.next_valid_sequence("addr1") == 12);
+
+        // only monotonic mode lets a sender skip ahead
+        let skipped = blockchain.process_transaction(balance(14));
+        if mode == SequenceMode::Strict {
+            assert!(skipped == Err(Error::SequenceGapTooLarge));
+            assert!(blockchain.next_valid_sequence("addr1") == 12);
+        } else {
+            assert!(skipped.is_ok());
+            assert!(blockchain.next_valid_sequence("addr1") == 15);
+        }
+        // the sequence it returns is always accepted
+        blockchain.process_transaction(balance(blockchain.next_valid_sequence("addr1")))?;
+    }
+
+    Ok(())
+}
+
+fn test_routing_mode() -> Result<(), Error> {
+    let contracts = || -> Vec<Box<dyn TokenContract>> {
+        vec![
+            Box::new(BasicToken::new("USDC".into(), vec!["addr1".into()], 1000)),
+            Box::new(BasicToken::new("DAI".into(), vec!["addr1".into()], 1000)),
+        ]
+    };
+    let transfer = |seq: u64, contract: &str, to: &str| {
+        Transaction::new("addr1", 100, contract, Method::Transfer)
+            .with_seq(seq)
+            .with_destination(to)
+    };
+    let balance = |blockchain: &Blockchain, contract: &str, address: &str| {
+        blockchain
+            .find_contract(contract)
+            .map(|c| c.balance_of(address.into()))
+            .unwrap_or_default()
+    };
+
+    let mut by_field = Blockchain::new(contracts());
+    by_field.process_transaction(transfer(1, "DAI", "addr2"))?;
+    assert!(balance(&by_field, "DAI", "addr2") == 100 && balance(&by_field, "USDC", "addr2") == 0);
+    assert!(by_field.process_transaction(transfer(2, "", "DAI")) == Err(Error::InvalidDestination));
+
+    // the destination picks the contract, and is paid, whatever the contract field says
+    let mut by_destination =
+        Blockchain::new(contracts()).with_routing_mode(RoutingMode::ByDestination);
+    by_destination.process_transaction(transfer(1, "USDC", "DAI"))?;
+    assert!(
+        balance(&by_destination, "DAI", "DAI") == 100
+            && balance(&by_destination, "DAI", "addr1") == 900
+    );
+    assert!(balance(&by_destination, "USDC", "addr1") == 1000);
+    assert!(by_destination.receipts()[0].contract.as_deref() == Some("DAI"));
+    assert!(
+        by_destination.process_transaction(transfer(2, "USDC", "addr2"))
+            == Err(Error::ContractNotFound)
+    );
+
+    Ok(())
+}
+
+fn test_simulate_with_events() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into()],
+        1000,
+    ))]);
+    let transfer = Transaction::new("addr1", 100, "USDC", Method::Transfer)
+        .with_seq(1)
+        .with_destination("addr2")
+        .with_memo("rent");
+
+    let (value, events) = blockchain.simulate_with_events(&transfer)?;
+    assert!(value == 0 && events.len() == 1 && blockchain.events().is_empty());
+    blockchain.process_transaction(transfer)?;
+    assert!(events == blockchain.events());
+
+    // nothing moves, so nothing is emitted
+    let balance = Transaction::new("addr1", 0, "USDC", Method::BalanceOf).with_seq(2);
+    assert!(blockchain.simulate_with_events(&balance)? == (900, vec![]));
+
+    Ok(())
+}
+
+fn test_max_transfer_bps_of_supply() -> Result<(), Error> {
+    // 10% of a 2000 supply
+    let token = BasicToken::new("USDC".into(), vec!["addr1".into(), "addr2".into()], 1000)
+        .with_owner("owner")
+        .with_max_transfer_bps_of_supply(1_000);
+    let mut blockchain = Blockchain::new(vec![Box::new(token)]);
+    let transfer = |seq: u64, amount: u64| {
+        Transaction::new("addr1", amount, "USDC", Method::Transfer)
+            .with_seq(seq)
+            .with_destination("addr2")
+    };
+
+    blockchain.process_transaction(transfer(1, 200))?;
+    assert!(blockchain.process_transaction(transfer(2, 201)) == Err(Error::TransferLimitExceeded));
+
+    // minting to 4000 doubles the cap
+    blockchain.batch_mint("USDC", "owner", vec![("addr3".into(), 2000)])?;
+    blockchain.process_transaction(transfer(3, 400))?;
+    assert!(blockchain.process_transaction(transfer(4, 401)) == Err(Error::TransferLimitExceeded));
+    let balance = Transaction::new("addr1", 0, "USDC", Method::BalanceOf).with_seq(5);
+    assert!(blockchain.process_transaction(balance)? == 400);
+
+    Ok(())
+}
+
+fn test_replay_log() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![
+        Box::new(BasicToken::new(
+            "USDC".into(),
+            vec!["addr1".into(), "addr2".into()],
+            1000,
+        )),
+        Box::new(BasicToken::new("WBTC".into(), vec!["addr3".into()], 1000)),
+    ]);
+    blockchain.credit_native("addr3", DEFAULT_DEPLOY_FEE);
+    blockchain.process_transaction(
+        Transaction::new("addr1", 100, "USDC", Method::Transfer)
+            .with_seq(1)
+            .with_destination("addr 2")
+            .with_memo("tab\there, newline\nthere \\N")
+            .with_category("payment"),
+    )?;
+    blockchain.produce_block();
+    blockchain.process_transaction(
+        Transaction::new("addr3", 0, "", Method::Deploy)
+            .with_seq(1)
+            .with_deploy("Dai", vec![("addr1".into(), 50), ("addr2".into(), 70)]),
+    )?;
+    blockchain.process_transaction(
+        Transaction::new("addr2", 500, "USDC", Method::ConditionalTransfer)
+            .with_seq(1)
+            .with_destination("addr1")
+            .with_condition(2000)
+            .with_valid_until(10),
+    )?;
+
+    let log = blockchain.export_log();
+    assert!(log.lines().filter(|l| l.starts_with("tx\t")).count() == 3);
+    let mut replayed = Blockchain::replay_log(&log)?;
+    assert!(replayed.state_root() == blockchain.state_root());
+    assert!(replayed.export_log() == log);
+    // the senders' sequences are spent on the replayed chain too
+    let replay = Transaction::new("addr1", 100, "USDC", Method::Transfer)
+        .with_seq(1)
+        .with_destination("addr2");
+    assert!(replayed.process_transaction(replay) == Err(Error::BadTransactionSequence));
+
+    let malformed = log.replacen("\ttransfer\t", "\tteleport\t", 1);
+    let line = 1 + log
+        .lines()
+        .position(|l| l.contains("\ttransfer\t"))
+        .unwrap();
+    assert!(Blockchain::replay_log(&malformed).err() == Some(Error::ParseError { line }));
+    assert!(Blockchain::replay_log("block").err() == Some(Error::ParseError { line: 1 }));
+
+    Ok(())
+}
+
+fn test_epochs() -> Result<(), Error> {
+    let contracts = || -> Vec<Box<dyn TokenContract>> {
+        vec![Box::new(BasicToken::new(
+            "USDC".into(),
+            vec!["addr1".into()],
+            1000,
+        ))]
+    };
+    assert!(
+        Blockchain::new(contracts()).with_blocks_per_epoch(0).err() == Some(Error::ZeroEpochLength)
+    );
+    assert!(Blockchain::new(contracts()).current_epoch() == 0);
+
+    let mut blockchain = Blockchain::new(contracts()).with_blocks_per_epoch(4)?;
+    let changes = Rc::new(RefCell::new(Vec::new()));
+    let seen = changes.clone();
+    blockchain.on_epoch_change(Box::new(move |epoch| seen.borrow_mut().push(epoch)));
+
+    blockchain
+        .process_transaction(Transaction::new("addr1", 0, "USDC", Method::BalanceOf).with_seq(1))?;
+    for _ in 0..2 {
+        blockchain.produce_block();
+    }
+    assert!(blockchain.current_epoch() == 0 && changes.borrow().is_empty());
+    // the 4th block starts epoch 1, and the 5th stays in it
+    blockchain.produce_block();
+    blockchain.produce_block();
+    assert!(blockchain.block_height == 5 && blockchain.current_epoch() == 1);
+    assert!(*changes.borrow() == vec![1]);
+
+    Ok(())
+}
+
+fn test_holdings() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![
+        Box::new(BasicToken::new("WBTC".into(), vec!["addr1".into()], 5)),
+        Box::new(BasicToken::new("USDC".into(), vec!["addr1".into()], 1000)),
+        Box::new(BasicToken::new("DAI".into(), vec!["addr1".into()], 1000)),
+    ]);
+    blockchain.process_transaction(
+        Transaction::new("addr1", 0, "DAI", Method::TransferAll)
+            .with_seq(1)
+            .with_destination("addr2"),
+    )?;
+
+    // the emptied DAI balance is left out
+    assert!(blockchain.holdings("addr1") == vec![("USDC".into(), 1000), ("WBTC".into(), 5)]);
+    assert!(blockchain.holdings("addr2") == vec![("DAI".into(), 1000)]);
+    assert!(blockchain.holdings("addr3").is_empty());
+
+    Ok(())
+}
+
+fn test_permissive_sequence_mode() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into()],
+        1000,
+    ))])
+    .with_sequence_mode(SequenceMode::Permissive);
+    let transfer = |seq: u64| {
+        Transaction::new("addr1", 100, "USDC", Method::Transfer)
+            .with_seq(seq)
+            .with_destination("addr2")
+    };
+
+    // in sequence, nothing to resync
+    blockchain.process_transaction(transfer(1))?;
+    blockchain.process_transaction(transfer(2))?;
+    assert!(blockchain.events_by_topic("sequence_resync").is_empty());
+
+    // the replay goes through, pulling the sequence back
+    blockchain.process_transaction(transfer(1))?;
+    let resync = Event::SequenceResync {
+        address: "addr1".into(),
+        from: 2,
+        to: 1,
+    };
+    assert!(blockchain.events_by_topic("sequence_resync") == vec![&resync]);
+    assert!(blockchain.next_valid_sequence("addr1") == 2);
+    let balance = Transaction::new("addr2", 0, "USDC", Method::BalanceOf).with_seq(1);
+    assert!(blockchain.process_transaction(balance)? == 300);
+
+    // a replay is still a replay everywhere else
+    assert!(blockchain.is_replay(&transfer(1)));
+    let mut monotonic = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into()],
+        1000,
+    ))]);
+    monotonic.process_transaction(transfer(1))?;
+    assert!(monotonic.process_transaction(transfer(1)) == Err(Error::BadTransactionSequence));
+
+    Ok(())
+}
+
+fn test_validators() -> Result<(), Error> {
+    let validators: Vec<String> = vec!["val1".into(), "val2".into(), "val3".into()];
+    let mut blockchain = Blockchain::new(vec![])
+        .with_validators(validators.clone())
+        .with_block_reward("miner", 50);
+    assert!(blockchain.current_proposer() == Some(&validators[0]));
+
+    let mut proposers = vec![];
+    for _ in 0..5 {
+        blockchain.produce_block();
+        proposers.push(blockchain.current_proposer().cloned().unwrap());
+    }
+    assert!(proposers == ["val2", "val3", "val1", "val2", "val3"]);
+    assert!(blockchain.blocks()[2].coinbase == Some(("val1".into(), 50)));
+    assert!(blockchain.native_balance_of("val1") == 50);
+    assert!(blockchain.native_balance_of("val2") == 100);
+    assert!(blockchain.native_balance_of("val3") == 100);
+    assert!(blockchain.native_balance_of("miner") == 0);
+
+    // without validators the coinbase is paid as before
+    let mut blockchain = Blockchain::new(vec![])
+        .with_validators(vec![])
+        .with_block_reward("miner", 50);
+    blockchain.produce_block();
+    assert!(blockchain.current_proposer().is_none());
+    assert!(blockchain.native_balance_of("miner") == 50);
+
+    Ok(())
+}
+
+fn test_total_supply_u128() -> Result<(), Error> {
+    let mut token = BasicToken::new("USDC".into(), vec!["addr1".into()], 1000).with_owner("owner");
+    let near_max = u64::MAX - 1;
+    token.batch_mint(
+        "owner",
+        vec![
+            ("addr2".into(), near_max),
+            ("addr3".into(), near_max),
+            ("addr4".into(), near_max),
+        ],
+    )?;
+
+    assert!(token.total_supply_u128() == 1000 + 3 * near_max as u128);
+    assert!(token.total_supply_u128() > u64::MAX as u128);
+    assert!(token.total_supply() == u64::MAX);
+
+    Ok(())
+}
+
+fn test_transfer_cooldown() -> Result<(), Error> {
+    let token = BasicToken::new("USDC".into(), vec!["addr1".into(), "addr2".into()], 1000)
+        .with_transfer_cooldown(3);
+    let mut blockchain = Blockchain::new(vec![Box::new(token)]);
+    let transfer = |sender: &str, seq: u64, to: &str| {
+        Transaction::new(sender, 100, "USDC", Method::Transfer)
+            .with_seq(seq)
+            .with_destination(to)
+    };
+
+    // sent in block 1, so addr1 may send again from block 4
+    blockchain.process_transaction(transfer("addr1", 1, "addr2"))?;
+    assert!(
+        blockchain.simulate(&transfer("addr1", 2, "addr2")).err() == Some(Error::CooldownActive)
+    );
+    assert!(
+        blockchain.process_transaction(transfer("addr1", 2, "addr2")) == Err(Error::CooldownActive)
+    );
+
+    // receiving doesn't start a cooldown, and doesn't wait one out
+    blockchain.process_transaction(transfer("addr2", 1, "addr1"))?;
+    assert!(
+        blockchain.process_transaction(transfer("addr1", 3, "addr2")) == Err(Error::CooldownActive)
+    );
+
+    blockchain.produce_block();
+    assert!(blockchain.block_height == 3);
+    blockchain.process_transaction(transfer("addr1", 4, "addr2"))?;
+    let balance = Transaction::new("addr1", 0, "USDC", Method::BalanceOf).with_seq(5);
+    assert!(blockchain.process_transaction(balance)? == 900);
+
+    Ok(())
+}
+
+fn test_fee_calculator() -> Result<(), Error> {
+    // 10 per transaction, and double that for transfers over 500
+    struct TieredFee;
+    impl FeeCalculator for TieredFee {
+        fn calculate(&self, transaction: &Transaction, _chain: &Blockchain) -> u64 {
+            if transaction.method.is_transfer() && transaction.amount > 500 {
+                20
+            } else {
+                10
+            }
+        }
+    }
+
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into()],
+        1000,
+    ))])
+    .with_fee_calculator(Box::new(TieredFee));
+    blockchain.credit_native("addr1", 35);
+    let transfer = |seq: u64, amount: u64| {
+        Transaction::new("addr1", amount, "USDC", Method::Transfer)
+            .with_seq(seq)
+            .with_destination("addr2")
+    };
+
+    assert!(blockchain.transaction_fee(&transfer(1, 500)) == 10);
+    assert!(blockchain.transaction_fee(&transfer(1, 501)) == 20);
+    blockchain.process_transaction(transfer(1, 100))?;
+    assert!(blockchain.native_balance_of("addr1") == 25);
+    blockchain.process_transaction(transfer(2, 600))?;
+    assert!(blockchain.native_balance_of("addr1") == 5);
+
+    // a sender that can't pay is rejected before its sequence is used up
+    assert!(blockchain.process_transaction(transfer(3, 100)) == Err(Error::InsufficientFeeBalance));
+    assert!(blockchain.next_valid_sequence("addr1") == 3);
+
+    // the default flat fee charges nothing
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into()],
+        1000,
+    ))]);
+    assert!(blockchain.transaction_fee(&transfer(1, 600)) == 0);
+    blockchain.process_transaction(transfer(1, 600))?;
+
+    Ok(())
+}
+
+fn test_random_beacon() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into()],
+        1000,
+    ))]);
+    assert!(blockchain.current_randomness() == 0);
+
+    blockchain.process_transaction(
+        Transaction::new("addr1", 100, "USDC", Method::Transfer)
+            .with_seq(1)
+            .with_destination("addr2"),
+    )?;
+    let first = blockchain.current_randomness();
+    blockchain.produce_block();
+    let second = blockchain.current_randomness();
+    assert!(first == blockchain.blocks()[0].random_beacon());
+    assert!(first != second);
+
+    // the same history gives the same randomness
+    let replayed = Blockchain::replay_log(&blockchain.export_log())?;
+    assert!(replayed.blocks()[0].random_beacon() == first);
+    assert!(replayed.current_randomness() == second);
+
+    Ok(())
+}
+
+fn test_disabled_methods() -> Result<(), Error> {
+    let token = BasicToken::new("USDC".into(), vec!["addr1".into()], 1000).with_owner("owner");
+    let mut blockchain = Blockchain::new(vec![Box::new(token)]);
+    let transfer = |seq: u64| {
+        Transaction::new("addr1", 100, "USDC", Method::Transfer)
+            .with_seq(seq)
+            .with_destination("addr2")
+    };
+    let balance = |seq: u64| Transaction::new("addr1", 0, "USDC", Method::BalanceOf).with_seq(seq);
+
+    assert!(
+        blockchain.set_method_enabled("USDC", "addr1", Method::Transfer, false)
+            == Err(Error::Unauthorized)
+    );
+    blockchain.set_method_enabled("USDC", "owner", Method::Transfer, false)?;
+    assert!(blockchain.process_transaction(transfer(1)) == Err(Error::MethodDisabled));
+    assert!(blockchain.process_transaction(balance(2))? == 1000);
+
+    // nor can any other kind of transfer get around it
+    let other_transfers = [
+        Method::TransferAll,
+        Method::TransferPartial,
+        Method::ConditionalTransfer,
+    ];
+    for (seq, method) in (3..).zip(other_transfers) {
+        let r = blockchain.process_transaction(Transaction {
+            method,
+            ..transfer(seq)
+        });
+        assert!(r == Err(Error::MethodDisabled));
+    }
+    assert!(blockchain.process_transaction(balance(6))? == 1000);
+
+    blockchain.set_method_enabled("USDC", "owner", Method::Transfer, true)?;
+    blockchain.process_transaction(transfer(7))?;
+    assert!(blockchain.process_transaction(balance(8))? == 900);
+
+    Ok(())
+}
+
+fn test_genesis_height() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into()],
+        1000,
+    ))])
+    .with_genesis_height(1000)
+    .with_confirmations_required(2)
+    .with_archive(true);
+    assert!(blockchain.block_height == 1000 && blockchain.genesis_height() == 1000);
+    assert!(blockchain.balance_at("USDC", "addr1", 1000)? == 1000);
+
+    assert!(blockchain.produce_block() == 1001);
+    for seq in 1..=3 {
+        blockchain.process_transaction(
+            Transaction::new("addr1", 100, "USDC", Method::Transfer)
+                .with_seq(seq)
+                .with_destination("addr2"),
+        )?;
+    }
+    assert!(blockchain.blocks()[0].height == 1001 && blockchain.block_height == 1004);
+    assert!(blockchain.receipts()[0].block_height == 1002);
+    assert!(blockchain.balance_at("USDC", "addr2", 1002)? == 100);
+
+    // genesis is final, whatever the confirmations
+    assert!(blockchain.is_final(1000) && blockchain.is_final(1002) && !blockchain.is_final(1003));
+    assert!(blockchain.rollback_to_height(999) == Err(Error::CannotRollbackFinalized));
+    blockchain.rollback_to_height(1002)?;
+    assert!(blockchain.block_height == 1002 && blockchain.blocks().len() == 2);
+    assert!(blockchain.balances_of("USDC", &["addr2".into()])? == vec![100]);
+    assert!(blockchain.verify_deterministic());
+
+    let replayed = Blockchain::replay_log(&blockchain.export_log())?;
+    assert!(replayed.genesis_height() == 1000);
+    assert!(replayed.state_root() == blockchain.state_root());
+
+    Ok(())
+}
+
+fn test_assert_balances() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into(), "addr2".into(), "addr3".into()],
+        1000,
+    ))]);
+    blockchain.process_transaction(
+        Transaction::new("addr1", 100, "USDC", Method::Transfer)
+            .with_seq(1)
+            .with_destination("addr2"),
+    )?;
+
+    // addr3 isn't listed, so it isn't checked
+    assert!(blockchain
+        .assert_balances("USDC", &[("addr1".into(), 900), ("addr2".into(), 1100)])
+        .is_ok());
+
+    let report = blockchain.assert_balances(
+        "USDC",
+        &[
+            ("addr2".into(), 1000),
+            ("addr1".into(), 900),
+            ("addr4".into(), 5),
+        ],
+    );
+    assert!(report == Err("addr2: expected 1000, got 1100\naddr4: expected 5, got 0\n".into()));
+    assert!(blockchain.assert_balances("DAI", &[]) == Err("contract DAI not found".into()));
+
+    Ok(())
+}
+
+fn test_fractional_reserve() -> Result<(), Error> {
+    // 100 held in reserve at 10% backs 1000 of loans
+    let mut token = BasicToken::new("USDC".into(), vec!["USDC".into()], 100)
+        .with_reserve_ratio(1_000)
+        .with_owner("owner");
+
+    assert!(token.lend("addr1", "addr1", 600) == Err(Error::Unauthorized));
+    token.lend("owner", "addr1", 600)?;
+    token.lend("owner", "addr2", 400)?;
+    assert!(token.outstanding_loans() == 1000 && token.total_supply() == 1100);
+    assert!(token.lend("owner", "addr1", 1) == Err(Error::ReserveRatioBreached));
+    assert!(token.outstanding_loans() == 1000 && token.balance_of("addr1".into()) == 600);
+
+    // repaying destroys the tokens and frees up capacity
+    assert!(token.repay("addr2", 1000) == Err(Error::NotEnoughBalance));
+    assert!(token.repay("addr1", 500)? == 500);
+    assert!(token.outstanding_loans() == 500 && token.balance_of("addr1".into()) == 100);
+    token.lend("owner", "addr3", 500)?;
+    assert!(token.lend("owner", "addr3", 1) == Err(Error::ReserveRatioBreached));
+
+    // a token that wasn't set up to lend doesn't
+    let mut plain = BasicToken::new("DAI".into(), vec!["DAI".into()], 100).with_owner("owner");
+    assert!(plain.lend("owner", "addr1", 1) == Err(Error::LendingNotEnabled));
+
+    // a loan creates tokens like minting does, so it's held to the same limits
+    let lender = || {
+        BasicToken::new("DAI".into(), vec!["DAI".into()], 100)
+            .with_reserve_ratio(1_000)
+            .with_owner("owner")
+    };
+    let mut capped = lender().with_supply_cap(150);
+    assert!(capped.lend("owner", "addr1", 51) == Err(Error::SupplyCapExceeded));
+    capped.lend("owner", "addr1", 50)?;
+    let mut full = lender().with_max_accounts(1);
+    assert!(full.lend("owner", "addr1", 1) == Err(Error::AccountLimitReached));
+    let mut allowlisted = lender().with_recipient_allowlist();
+    assert!(allowlisted.lend("owner", "addr1", 1) == Err(Error::RecipientNotAllowed));
+    allowlisted.allow_recipient("owner", "addr1")?;
+    allowlisted.lend("owner", "addr1", 1)?;
+
+    Ok(())
+}
+
+fn test_max_tx_size() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into()],
+        1000,
+    ))])
+    .with_max_tx_size(128);
+    blockchain.credit_native("addr1", 10 * DEFAULT_DEPLOY_FEE);
+    let transfer = Transaction::new("addr1", 100, "USDC", Method::Transfer)
+        .with_seq(1)
+        .with_destination("addr2");
+    // 5 + 4 + 1 + 5 bytes of strings and method, and 6 numbers
+    assert!(transfer.size() == 63);
+    blockchain.process_transaction(transfer.clone().with_memo("rent"))?;
+
+    let long_memo = transfer.clone().with_seq(2).with_memo(&"x".repeat(100));
+    assert!(long_memo.size() == 163);
+    assert!(blockchain.process_transaction(long_memo) == Err(Error::TransactionTooLarge));
+
+    let recipients = (0..10).map(|i| (format!("addr{}", i), 1)).collect();
+    let deploy = Transaction::new("addr1", 0, "", Method::Deploy)
+        .with_seq(2)
+        .with_deploy("Dai", recipients);
+    assert!(blockchain.process_transaction(deploy) == Err(Error::TransactionTooLarge));
+    // rejected before its sequence is used
+    blockchain.process_transaction(transfer.with_seq(2))?;
+
+    Ok(())
+}
+
+fn test_tips() -> Result<(), Error> {
+    let mut blockchain = Blockchain::new(vec![Box::new(BasicToken::new(
+        "USDC".into(),
+        vec!["addr1".into()],
+        1000,
+    ))])
+    .with_validators(vec!["val1".into(), "val2".into()])
+    .with_fee_calculator(Box::new(FlatFee::new(10)));
+    blockchain.credit_native("addr1", 100);
+    let transfer = |seq: u64, tip: u64| {
+        Transaction::new("addr1", 100, "USDC", Method::Transfer)
+            .with_seq(seq)
+            .with_destination("addr2")
+            .with_tip(tip)
+    };
+
+    // block 1 is val2's, block 2 val1's. the base fee is burnt.
+    blockchain.process_transaction(transfer(1, 5))?;
+    blockchain.process_transaction(transfer(2, 7))?;
+    assert!(blockchain.native_balance_of("val2") == 5);
+    assert!(blockchain.native_balance_of("val1") == 7);
+    assert!(blockchain.native_balance_of("addr1") == 100 - 15 - 17);
+    assert!(blockchain.blocks()[1].coinbase == Some(("val1".into(), 7)));
+
+    // the fee and the tip have to be affordable together
+    assert!(blockchain.process_transaction(transfer(3, 59)) == Err(Error::InsufficientFeeBalance));
+    blockchain.process_transaction(transfer(3, 58))?;
+    assert!(
+        blockchain.native_balance_of("val2") == 63 && blockchain.native_balance_of("addr1") == 0
+    );
+
+    // a failed transaction burns its tip, as no block pays it out
+    blockchain.credit_native("addr1", 20);
+    let too_much = Transaction::new("addr1", 5000, "USDC", Method::Transfer)
+        .with_seq(4)
+        .with_destination("addr2")
+        .with_tip(10);
+    assert!(blockchain.process_transaction(too_much) == Err(Error::NotEnoughBalance));
+    assert!(
+        blockchain.native_balance_of("addr1") == 0 && blockchain.native_balance_of("val1") == 7
+    );
+
+    // a tip too large to add to the gas can't be afforded, however much is held
+    blockchain.credit_native("rich", u64::MAX);
+    let overflowing = Transaction::new("rich", 0, "USDC", Method::BalanceOf)
+        .with_seq(1)
+        .with_gas(50_000, 1)
+        .with_tip(u64::MAX);
+    assert!(blockchain.process(overflowing) == Err(Error::InsufficientFeeBalance));
+    assert!(blockchain.native_balance_of("rich") == u64::MAX);
+
+    Ok(())
+}
+
+fn test_burn_address() -> Result<(), Error> {
+    let token = BasicToken::new("USDC".into(), vec!["addr1".into()], 1000).with_burn_address("0x0");
+    let mut blockchain = Blockchain::new(vec![Box::new(token)]);
+    let burn = Transaction::new("addr1", 300, "USDC", Method::Transfer)
+        .with_seq(1)
+        .with_destination("0x0");
+
+    let (_, predicted) = blockchain.simulate_with_events(&burn)?;
+    blockchain.process_transaction(burn)?;
+    let burnt = Event::Burn {
+        contract: "USDC".into(),
+        from: "addr1".into(),
+        amount: 300,
+    };
+    assert!(blockchain.events() == [burnt.clone()] && predicted == [burnt]);
+    assert!(blockchain.balances_of("USDC", &["addr1".into(), "0x0".into()])? == vec![700, 0]);
+    assert!(blockchain.top_holders("USDC", usize::MAX)? == vec![("addr1".into(), 700)]);
+    assert!(blockchain.holdings("0x0").is_empty());
+
+    // anywhere else is an ordinary transfer
+    blockchain.process_transaction(
+        Transaction::new("addr1", 100, "USDC", Method::Transfer)
+            .with_seq(2)
+            .with_destination("addr2"),
+    )?;
+    assert!(blockchain.events_by_topic("transfer").len() == 1);
+    assert!(blockchain.events_by_topic("burn").len() == 1);
+
+    let mut token =
+        BasicToken::new("DAI".into(), vec!["addr1".into()], 1000).with_burn_address("0x0");
+    token.transfer("addr1".into(), 250, "0x0".into())?;
+    assert!(token.total_supply() == 750 && token.balance_of("0x0".into()) == 0);
+
+    // a burn goes through even when the token only sends to allowed recipients
+    let mut token = BasicToken::new("DAI".into(), vec!["addr1".into()], 1000)
+        .with_burn_address("0x0")
+        .with_recipient_allowlist();
+    let r = token.transfer("addr1".into(), 100, "addr2".into());
+    assert!(r == Err(Error::RecipientNotAllowed));
+    token.transfer("addr1".into(), 250, "0x0".into())?;
+    assert!(token.total_supply() == 750);
+
+    Ok(())
+}
+
+fn main() {
+    let tests: Vec<fn() -> Result<(), Error>> = vec![
+        test_blockchain,
+        test_gas,
+        test_dump_state,
+        test_method_names,
+        test_balance_history,
+        test_default_sender,
+        test_upgrade_contract,
+        test_valid_until,
+        test_airdrop_weighted,
+        test_metrics,
+        test_transfer_all,
+        test_check_transaction,
+        test_contract_kind,
+        test_balances_of,
+        test_vault,
+        test_memo,
+        test_top_holders,
+        test_genesis_nonce,
+        test_conditional_transfer,
+        test_account_lifecycle,
+        test_transfer_hook,
+        test_is_replay,
+        test_block_reward,
+        test_contract_addresses,
+        test_iter_balances,
+        test_prune_zero_balances,
+        test_receipt_builder,
+        test_blacklist,
+        test_verify_deterministic,
+        test_math,
+        test_max_accounts,
+        test_signing,
+        test_base_fee,
+        test_import_sequences,
+        test_subscribe,
+        test_ranking,
+        test_transfer_partial,
+        test_from_csv,
+        test_rollback,
+        test_process_atomic,
+        test_fee_rounding,
+        test_counts,
+        test_transfer_erc20,
+        test_batch_mint,
+        test_self_transfer_policy,
+        test_genesis_supply,
+        test_process_idempotent,
+        test_balance_histogram,
+        test_error_precedence,
+        test_property_harness,
+        test_storage_capacity,
+        test_export_accounts,
+        test_min_gas_price,
+        test_history_by_category,
+        test_balance_changes,
+        test_max_sequence_gap,
+        test_explain,
+        test_volume_cap,
+        test_archive,
+        test_valid_from,
+        test_relay,
+        test_nonce_fillers,
+        test_noop,
+        test_whitelist,
+        test_receipt_contract,
+        test_arithmetic_mode,
+        test_derive_contract_address,
+        test_deploy,
+        test_events_by_topic,
+        test_advance_time,
+        test_recipient_allowlist,
+        test_bridge,
+        test_next_valid_sequence,
+        test_routing_mode,
+        test_simulate_with_events,
+        test_max_transfer_bps_of_supply,
+        test_replay_log,
+        test_epochs,
+        test_holdings,
+        test_permissive_sequence_mode,
+        test_validators,
+        test_total_supply_u128,
+        test_transfer_cooldown,
+        test_fee_calculator,
+        test_random_beacon,
+        test_disabled_methods,
+        test_genesis_height,
+        test_assert_balances,
+        test_fractional_reserve,
+        test_max_tx_size,
+        test_tips,
+        test_burn_address,
+        #[cfg(feature = "test-utils")]
+        test_set_balance,
+    ];
+    for test in tests {
+        let r = test();
+        if r.is_err() {
+            let s = format!("{:?}", r);
+            panic!("{}", s)
+        }
     }
 }
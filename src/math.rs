@@ -0,0 +1,72 @@
+// basis point arithmetic shared by every percentage based feature. a basis point is
+// 1/10000, and intermediates are widened to u128 so nothing overflows along the way.
+
+pub const BPS_DENOMINATOR: u64 = 10_000;
+
+// how a fractional base unit resolves. with Floor, whoever pays a fee never pays more
+// than the exact amount.
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub enum RoundingMode {
+    #[default]
+    Floor,
+    Ceil,
+    // to the nearest unit, halves rounding up
+    Round,
+}
+
+// what adding past u64::MAX does
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub enum ArithmeticMode {
+    // fail
+    #[default]
+    Checked,
+    // clamp at u64::MAX
+    Saturating,
+    // wrap around past zero, for chaos testing
+    Wrapping,
+}
+
+impl ArithmeticMode {
+    // a + b, or None if that overflows in checked mode
+    pub fn add(self, a: u64, b: u64) -> Option<u64> {
+        match self {
+            ArithmeticMode::Checked => a.checked_add(b),
+            ArithmeticMode::Saturating => Some(a.saturating_add(b)),
+            ArithmeticMode::Wrapping => Some(a.wrapping_add(b)),
+        }
+    }
+}
+
+// value * bps / 10000, rounded down. saturates at u64::MAX when bps is over 100%.
+pub fn mul_bps(value: u64, bps: u16) -> u64 {
+    mul_bps_rounded(value, bps, RoundingMode::Floor)
+}
+
+// value * bps / 10000, rounded as `rounding` says. saturates like mul_bps.
+pub fn mul_bps_rounded(value: u64, bps: u16, rounding: RoundingMode) -> u64 {
+    let numerator = value as u128 * bps as u128;
+    let denominator = BPS_DENOMINATOR as u128;
+    let (quotient, remainder) = (numerator / denominator, numerator % denominator);
+    let result = match rounding {
+        RoundingMode::Floor => quotient,
+        RoundingMode::Ceil => quotient + (remainder > 0) as u128,
+        RoundingMode::Round => quotient + (remainder * 2 >= denominator) as u128,
+    };
+    result.min(u64::MAX as u128) as u64
+}
+
+// value * bps / 10000 for every one of `periods`, like simple interest, rounded down once at
+// the end. saturates at u64::MAX.
+pub fn mul_bps_periods(value: u64, bps: u16, periods: u64) -> u64 {
+    let total = (value as u128 * bps as u128)
+        .checked_mul(periods as u128)
+        .map_or(u128::MAX, |numerator| numerator / BPS_DENOMINATOR as u128);
+    total.min(u64::MAX as u128) as u64
+}
+
+// split value into (the bps part, the rest). the two always sum to exactly value, and
+// bps over 100% is treated as 100%.
+pub fn split_bps(value: u64, bps: u16) -> (u64, u64) {
+    let part = mul_bps(value, bps.min(BPS_DENOMINATOR as u16));
+    (part, value - part)
+}
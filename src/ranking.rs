@@ -0,0 +1,13 @@
+// the one ordering every ranking in the crate uses, so equal balances never come out in
+// HashMap order: largest balance first, then address ascending. sorting is stable, so
+// entries that tie on both keep the order they were given in.
+
+use std::cmp::Ordering;
+
+pub fn by_balance_desc(a: &(String, u64), b: &(String, u64)) -> Ordering {
+    b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0))
+}
+
+pub fn rank_by_balance(entries: &mut [(String, u64)]) {
+    entries.sort_by(by_balance_desc);
+}
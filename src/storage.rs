@@ -0,0 +1,55 @@
+// the map type the hottest state is stored in: token ledgers, sequences and native
+// balances. by default it's a plain HashMap with SipHash. the `fxhash` feature swaps in
+// FxHash, which is a lot cheaper for large simulations but isn't DoS resistant, so it's
+// only meant for tests.
+
+use std::collections::HashMap;
+
+#[cfg(feature = "fxhash")]
+pub type Map<K, V> = HashMap<K, V, std::hash::BuildHasherDefault<FxHasher>>;
+#[cfg(not(feature = "fxhash"))]
+pub type Map<K, V> = HashMap<K, V>;
+
+// an empty map with room for at least `capacity` entries
+pub fn with_capacity<K, V>(capacity: usize) -> Map<K, V> {
+    Map::with_capacity_and_hasher(capacity, Default::default())
+}
+
+// the hasher rustc uses internally: one rotate, xor and multiply per word
+#[cfg(feature = "fxhash")]
+#[derive(Default, Clone, Copy)]
+pub struct FxHasher {
+    hash: u64,
+}
+
+#[cfg(feature = "fxhash")]
+impl FxHasher {
+    const SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+
+    fn add_to_hash(&mut self, word: u64) {
+        self.hash = (self.hash.rotate_left(5) ^ word).wrapping_mul(Self::SEED);
+    }
+}
+
+#[cfg(feature = "fxhash")]
+impl std::hash::Hasher for FxHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(8) {
+            let mut word = [0u8; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.add_to_hash(u64::from_le_bytes(word));
+        }
+    }
+    fn write_u8(&mut self, i: u8) {
+        self.add_to_hash(i as u64);
+    }
+    fn write_u64(&mut self, i: u64) {
+        self.add_to_hash(i);
+    }
+    fn write_usize(&mut self, i: usize) {
+        self.add_to_hash(i as u64);
+    }
+    fn finish(&self) -> u64 {
+        self.hash
+    }
+}
@@ -0,0 +1,151 @@
+// the line format of `Blockchain::export_log`. fields are tab separated and escaped like
+// postgres' COPY text format: a backslash, tab, newline or carriage return in a string
+// gets a backslash in front, and \N stands for a field that isn't set.
+//
+//   genesis  <height>                       the height the chain started at
+//   contract <address>                      a contract as deployed
+//   balance  <contract> <address> <amount>  one of its genesis balances
+//   block    <gas_used>                     a block with no transactions
+//   tx       <gas_used> <transaction...>    a block with one transaction
+
+use crate::{Condition, DeployParams, Method, Transaction};
+
+const NULL: &str = "\\N";
+
+pub fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\t' => out.push_str("\\t"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+// None for a dangling backslash or an unknown escape
+pub fn unescape(s: &str) -> Option<String> {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        out.push(match chars.next()? {
+            '\\' => '\\',
+            't' => '\t',
+            'n' => '\n',
+            'r' => '\r',
+            _ => return None,
+        });
+    }
+    Some(out)
+}
+
+fn optional<T: ToString>(value: Option<T>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_else(|| NULL.into())
+}
+
+// the fields of a tx line after its gas_used, every field of the transaction in the order
+// it's declared
+pub fn format_transaction(transaction: &Transaction) -> String {
+    let mut fields = vec![
+        escape(&transaction.sender),
+        transaction.sequence.to_string(),
+        transaction.amount.to_string(),
+        escape(&transaction.contract),
+        transaction.method.as_str().into(),
+        escape(&transaction.destination),
+        transaction.gas_limit.to_string(),
+        transaction.gas_price.to_string(),
+        optional(transaction.valid_until),
+        optional(transaction.valid_from),
+        optional(transaction.memo.as_deref().map(escape)),
+        optional(transaction.category.as_deref().map(escape)),
+        optional(transaction.condition.map(|c| c.min_balance)),
+        optional(
+            transaction
+                .signature
+                .as_ref()
+                .map(|s| s.iter().map(|b| format!("{:02x}", b)).collect::<String>()),
+        ),
+        transaction.max_fee.to_string(),
+        transaction.tip.to_string(),
+        optional(transaction.deploy.as_ref().map(|d| escape(&d.name))),
+    ];
+    // a deploy's balances trail the rest, an address and an amount per pair of fields
+    for (address, balance) in transaction.deploy.iter().flat_map(|d| &d.balances) {
+        fields.push(escape(address));
+        fields.push(balance.to_string());
+    }
+    fields.join("\t")
+}
+
+// the inverse of format_transaction, None if the fields are malformed
+pub fn parse_transaction(fields: &[&str]) -> Option<Transaction> {
+    let number = |field: &str| field.parse::<u64>().ok();
+    let optional_string = |field: &str| match field {
+        NULL => Some(None),
+        field => unescape(field).map(Some),
+    };
+    let optional_number = |field: &str| match field {
+        NULL => Some(None),
+        field => field.parse::<u64>().ok().map(Some),
+    };
+
+    let [sender, sequence, amount, contract, method, destination, gas_limit, gas_price, rest @ ..] =
+        fields
+    else {
+        return None;
+    };
+    let [valid_until, valid_from, memo, category, condition, signature, max_fee, tip, deploy, balances @ ..] =
+        rest
+    else {
+        return None;
+    };
+    let signature = match *signature {
+        NULL => None,
+        hex if hex.len() % 2 == 0 => Some(
+            (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+                .collect::<Option<Vec<u8>>>()?,
+        ),
+        _ => return None,
+    };
+    let deploy = match optional_string(deploy)? {
+        Some(name) if balances.len() % 2 == 0 => Some(DeployParams {
+            name,
+            balances: balances
+                .chunks(2)
+                .map(|pair| Some((unescape(pair[0])?, number(pair[1])?)))
+                .collect::<Option<Vec<(String, u64)>>>()?,
+        }),
+        None if balances.is_empty() => None,
+        _ => return None,
+    };
+
+    Some(Transaction {
+        sender: unescape(sender)?,
+        sequence: number(sequence)?,
+        amount: number(amount)?,
+        contract: unescape(contract)?,
+        method: method.parse::<Method>().ok()?,
+        destination: unescape(destination)?,
+        gas_limit: number(gas_limit)?,
+        gas_price: number(gas_price)?,
+        valid_until: optional_number(valid_until)?,
+        valid_from: optional_number(valid_from)?,
+        memo: optional_string(memo)?,
+        category: optional_string(category)?,
+        condition: optional_number(condition)?.map(|min_balance| Condition { min_balance }),
+        signature,
+        max_fee: number(max_fee)?,
+        tip: number(tip)?,
+        deploy,
+    })
+}